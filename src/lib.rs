@@ -1,5 +1,14 @@
+// The crate spells out `field: field` in struct literals, as it did before the shorthand existed.
+#![allow(clippy::redundant_field_names)]
+
 extern crate quickcheck;
 
-mod map;
+pub mod map;
+pub mod observer;
+pub mod set;
+pub mod stream;
 
 pub use map::TrieMap;
+pub use observer::TrieObserver;
+pub use set::TrieSet;
+pub use stream::StreamMatcher;
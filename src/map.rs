@@ -1,24 +1,52 @@
+use std::ascii;
 use std::collections::{BTreeMap, btree_map};
+use std::cmp::Ordering;
+use std::error;
 use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
 use std::iter;
 use std::mem;
+use std::ops::{self, Bound, Index, RangeBounds};
 
-use quickcheck;
+use observer::{NodeKind, TrieObserver};
 
+/// A map from byte strings to values, stored as a compressed trie.
+///
+/// The map can be given a `TrieObserver` to be told how its nodes change as keys are inserted.
+/// Maps without one use `()`.
 #[derive(Debug, Clone)]
-pub struct TrieMap<V> {
+pub struct TrieMap<V, O = ()> {
     root: TrieNode<V>,
     len: usize,
+    observer: O,
 }
 
-impl<V: Debug> TrieMap<V> {
+impl<V> TrieMap<V> {
     pub fn new() -> Self {
+        TrieMap::with_observer(())
+    }
+}
+
+impl<V, O: TrieObserver> TrieMap<V, O> {
+    /// Creates an empty map that reports the changes to its nodes to `observer`.
+    pub fn with_observer(observer: O) -> Self {
         TrieMap {
             root: TrieNode::empty(),
             len: 0,
+            observer: observer,
         }
     }
 
+    /// Returns a reference to the map's observer.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Returns a mutable reference to the map's observer.
+    pub fn observer_mut(&mut self) -> &mut O {
+        &mut self.observer
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -28,439 +56,3716 @@ impl<V: Debug> TrieMap<V> {
     }
 
     pub fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
-        println!("TrieMap.insert: {:?}", key);
-
-        let old_value = self.root.insert(key, value);
+        let old_value = self.root.insert(key, value, &mut self.observer);
 
         if old_value.is_none() {
             self.len += 1;
+        } else {
+            self.observer.value_replaced(key);
         }
 
+        self.debug_validate();
+
         old_value
     }
 
-    pub fn get<'a>(&'a mut self, key: &[u8]) -> Option<&'a V> {
-        println!("TrieMap.get: {:?}", key);
-
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
         self.root.get(key)
     }
 
-    /*
-    pub fn iter(&self) -> Iter<'a, V> {
-        Iter {
-            key: Vec::new(),
-            iter: self.root.iter(Vec::new())
+    pub fn get_mut<'a>(&'a mut self, key: &[u8]) -> Option<&'a mut V> {
+        self.root.get_mut(key)
+    }
+
+    /// Returns true if the map contains a value for `key`.
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.root.get(key).is_some()
+    }
+
+    /// Finds the longest key in the map that is a prefix of `key`, and returns its length along
+    /// with its value.
+    pub fn longest_prefix_match<'a>(&'a self, key: &[u8]) -> Option<(usize, &'a V)> {
+        self.common_prefixes(key).last()
+    }
+
+    /// Gets an iterator over every key in the map that is a prefix of `key`, from shortest to
+    /// longest. Each item is the length of the stored key, along with its value.
+    pub fn common_prefixes<'a, 'k>(&'a self, key: &'k [u8]) -> CommonPrefixes<'a, 'k, V> {
+        CommonPrefixes {
+            node: Some(NodeRef::Trie(&self.root)),
+            key: key,
+            pos: 0,
         }
     }
-    */
-}
 
-/*
-enum IterState<'a, V> {
-    Trie(TrieIter<'a, V>),
-    Prefix(PrefixNode<'a, V>),
-}
+    /// Gets a cursor that walks down the trie one byte at a time, starting at the empty key.
+    pub fn cursor<'a>(&'a self) -> Cursor<'a, V> {
+        Cursor {
+            root: &self.root,
+            node: NodeRef::Trie(&self.root),
+            offset: 0,
+            depth: 0,
+        }
+    }
 
-pub struct Iter<'a, V> {
-    key: Vec<u8>,
-    iter_state: IterState<'a, V>,
-}
+    /// Looks up `key` like `get`, but records every node the walk passes through, and why it
+    /// stopped where it did.
+    pub fn explain(&self, key: &[u8]) -> LookupTrace {
+        let mut steps = Vec::new();
+        let mut node = NodeRef::Trie(&self.root);
+        let mut pos = 0;
+
+        let stop = loop {
+            match node {
+                NodeRef::Trie(trie) => {
+                    let mut step = TraceStep {
+                        kind: NodeKind::Trie,
+                        depth: pos,
+                        consumed: 0,
+                        key_len: 0,
+                    };
+
+                    let byte = match key.get(pos) {
+                        Some(byte) => *byte,
+                        None => {
+                            steps.push(step);
+                            break if trie.value.is_some() {
+                                StopReason::Found
+                            } else {
+                                StopReason::NoValue
+                            };
+                        }
+                    };
 
-impl<'a, V> Iterator for Iter<'a, V> {
-    type Item = (Vec<u8>, &'a V);
+                    match trie.children.get(&byte) {
+                        Some(child) => {
+                            step.consumed = 1;
+                            steps.push(step);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next()
+                            pos += 1;
+                            node = NodeRef::new(child);
+                        }
+                        None => {
+                            steps.push(step);
+                            break StopReason::MissingChild(byte);
+                        }
+                    }
+                }
+                NodeRef::Prefix(prefix) => {
+                    let rest = &key[pos..];
+                    let matched = rest.iter()
+                        .zip(prefix.key.iter())
+                        .take_while(|&(lhs, rhs)| lhs == rhs)
+                        .count();
+
+                    steps.push(TraceStep {
+                        kind: NodeKind::Prefix,
+                        depth: pos,
+                        consumed: matched,
+                        key_len: prefix.key.len(),
+                    });
+
+                    pos += matched;
+
+                    if matched < prefix.key.len() {
+                        break if matched < rest.len() {
+                            StopReason::Diverged(matched)
+                        } else {
+                            StopReason::NoValue
+                        };
+                    }
+
+                    if pos == key.len() {
+                        break if prefix.value.is_some() {
+                            StopReason::Found
+                        } else {
+                            StopReason::NoValue
+                        };
+                    }
+
+                    match prefix.child {
+                        Some(ref child) => { node = NodeRef::new(child); }
+                        None => { break StopReason::MissingChild(key[pos]); }
+                    }
+                }
+            }
+        };
+
+        LookupTrace {
+            steps: steps,
+            matched: pos,
+            stop: stop,
+        }
     }
-}
-*/
 
-impl<'a, V: Debug> iter::FromIterator<(&'a [u8], V)> for TrieMap<V> {
-    fn from_iter<I: IntoIterator<Item=(&'a [u8], V)>>(iterator: I) -> Self {
-        let mut map = TrieMap::new();
-        for (key, value) in iterator.into_iter() {
-            map.insert(key, value);
+    /// Returns the value for `key`, or if it is not in the map, how far it got down the trie and
+    /// how it relates to the keys that are stored.
+    pub fn get_or_mismatch<'a>(&'a self, key: &[u8]) -> Result<&'a V, Mismatch> {
+        let mut node = NodeRef::Trie(&self.root);
+        let mut pos = 0;
+
+        // The length of the longest stored key that `key` goes on past.
+        let mut stored = None;
+
+        let kind = loop {
+            match node {
+                NodeRef::Trie(trie) => {
+                    let byte = match key.get(pos) {
+                        Some(byte) => byte,
+                        None => {
+                            match trie.value {
+                                Some(ref value) => { return Ok(value); }
+                                None if trie.count > 0 => { break MismatchKind::Prefix; }
+                                None => { break MismatchKind::Diverged; }
+                            }
+                        }
+                    };
+
+                    if trie.value.is_some() {
+                        stored = Some(pos);
+                    }
+
+                    match trie.children.get(byte) {
+                        Some(child) => {
+                            pos += 1;
+                            node = NodeRef::new(child);
+                        }
+                        None => {
+                            break stored.map_or(MismatchKind::Diverged, MismatchKind::Extends);
+                        }
+                    }
+                }
+                NodeRef::Prefix(prefix) => {
+                    let rest = &key[pos..];
+
+                    if let Some(offset) = prefix.find_difference(rest) {
+                        pos += offset;
+                        break stored.map_or(MismatchKind::Diverged, MismatchKind::Extends);
+                    }
+
+                    // The key ends inside of our compressed key.
+                    if rest.len() < prefix.key.len() {
+                        pos += rest.len();
+                        break MismatchKind::Prefix;
+                    }
+
+                    pos += prefix.key.len();
+
+                    if pos == key.len() {
+                        match prefix.value {
+                            Some(ref value) => { return Ok(value); }
+                            None => { break MismatchKind::Prefix; }
+                        }
+                    }
+
+                    if prefix.value.is_some() {
+                        stored = Some(pos);
+                    }
+
+                    match prefix.child {
+                        Some(ref child) => { node = NodeRef::new(child); }
+                        None => {
+                            break stored.map_or(MismatchKind::Diverged, MismatchKind::Extends);
+                        }
+                    }
+                }
+            }
+        };
+
+        Err(Mismatch {
+            matched: pos,
+            kind: kind,
+        })
+    }
+
+    /// Tells how `key` relates to the keys in the map, in a single walk down the trie. If more than
+    /// one answer applies, the first in the order of `KeyRelation`'s variants wins.
+    pub fn classify(&self, key: &[u8]) -> KeyRelation {
+        match self.get_or_mismatch(key) {
+            Ok(_) => KeyRelation::Stored,
+            Err(mismatch) => {
+                match mismatch.kind {
+                    MismatchKind::Prefix => KeyRelation::Prefix,
+                    MismatchKind::Extends(_) => KeyRelation::Extends,
+                    MismatchKind::Diverged => KeyRelation::Unrelated,
+                }
+            }
         }
-        map
     }
-}
 
-/*
-impl<V> fmt::Debug for TrieMap<V>
-    where V: fmt::Debug,
-{
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_map().entries(self.iter()).finish()
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    pub fn entry<'a>(&'a mut self, key: &[u8]) -> Entry<'a, V, O> {
+        let key = key.to_owned();
+        let mut counts = Vec::new();
+
+        match self.root.find_entry(&key, 0, &mut counts) {
+            EntryNode::Occupied(value) => {
+                Entry::Occupied(OccupiedEntry {
+                    key: key,
+                    value: value,
+                    observer: &mut self.observer,
+                })
+            }
+            EntryNode::Vacant(node, pos) => {
+                Entry::Vacant(VacantEntry {
+                    key: key,
+                    pos: pos,
+                    node: node,
+                    counts: counts,
+                    len: &mut self.len,
+                    observer: &mut self.observer,
+                })
+            }
+        }
     }
-}
 
-impl<V> quickcheck::Arbitrary for TrieMap<V>
-    where V: Clone + quickcheck::Arbitrary,
-{
-    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> TrieMap<V> {
-        use std::collections::HashMap;
+    /// Removes a key from the map, returning the value at the key if the key was previously in the
+    /// map.
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let value = self.root.remove(key, &mut self.observer);
 
-        let items: HashMap<Vec<u8>, V> = quickcheck::Arbitrary::arbitrary(g);
-        TrieMap::from_iter(items.into_iter())
+        if value.is_some() {
+            self.len -= 1;
+        }
+
+        self.debug_validate();
+
+        value
     }
 
-    fn shrink(&self) -> Box<Iterator<Item=TrieMap<V>>> {
-        use std::collections::HashMap;
+    /// Removes a key from the map, returning the stored key and value if the key was previously in
+    /// the map.
+    pub fn remove_entry(&mut self, key: &[u8]) -> Option<(Vec<u8>, V)> {
+        self.remove(key).map(|value| (key.to_owned(), value))
+    }
 
-        let items: HashMap<Vec<u8>, V> = self.iter()
-            .map(|(key, value)| (key.to_owned(), value.clone()))
-            .collect();
+    /// Returns the first entry in the map, which has the smallest key.
+    pub fn first_key_value(&self) -> Option<(Vec<u8>, &V)> {
+        self.iter().next()
+    }
 
-        Box::new(items.shrink()
-            .map(|items| items.into_iter().collect::<TrieMap<V>>()))
+    /// Returns the last entry in the map, which has the largest key.
+    pub fn last_key_value(&self) -> Option<(Vec<u8>, &V)> {
+        self.iter().next_back()
     }
-}
-*/
 
-#[derive(Clone, Debug)]
-enum Node<V> {
-    Trie(TrieNode<V>),
-    Prefix(PrefixNode<V>),
-}
+    /// Returns the entry with the greatest key that is less than or equal to `key`.
+    pub fn lower_bound<'a>(&'a self, key: &[u8]) -> Option<(Vec<u8>, &'a V)> {
+        self.range((Bound::Unbounded, Bound::Included(key))).next_back()
+    }
 
-enum InsertResult<V> {
-    Ok(Option<V>),
-    Burst(TrieNode<V>),
-}
+    /// Returns the entry with the smallest key that is greater than or equal to `key`.
+    pub fn upper_bound<'a>(&'a self, key: &[u8]) -> Option<(Vec<u8>, &'a V)> {
+        self.range((Bound::Included(key), Bound::Unbounded)).next()
+    }
 
-impl<V: Debug> Node<V> {
-    fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
-        println!("Node.insert: {:?}", key);
+    /// Returns the number of keys in the map that are strictly less than `key`.
+    pub fn rank(&self, key: &[u8]) -> usize {
+        self.root.rank(key)
+    }
 
-        let trie = match *self {
-            Node::Trie(ref mut node) => {
-                return node.insert(key, value);
+    /// Returns the `n`th smallest key in the map, counting from zero.
+    pub fn select(&self, n: usize) -> Option<Vec<u8>> {
+        self.nth(n).map(|(key, _)| key)
+    }
+
+    /// Returns the `n`th entry in the map in key order, counting from zero.
+    pub fn nth(&self, n: usize) -> Option<(Vec<u8>, &V)> {
+        if n >= self.len {
+            return None;
+        }
+
+        let mut key = Vec::new();
+        let value = self.root.nth(n, &mut key);
+
+        Some((key, value))
+    }
+
+    /// Returns the number of keys in the map that start with `prefix`.
+    pub fn count_prefix(&self, prefix: &[u8]) -> usize {
+        self.root.count_prefix(prefix)
+    }
+
+    /// Removes and returns the first entry in the map.
+    pub fn pop_first(&mut self) -> Option<(Vec<u8>, V)> {
+        match self.keys().next() {
+            Some(key) => self.remove_entry(&key),
+            None => None,
+        }
+    }
+
+    /// Removes and returns the last entry in the map.
+    pub fn pop_last(&mut self) -> Option<(Vec<u8>, V)> {
+        match self.keys().next_back() {
+            Some(key) => self.remove_entry(&key),
+            None => None,
+        }
+    }
+
+    /// Gets an iterator over the entries of the map, sorted by key.
+    pub fn iter<'a>(&'a self) -> Iter<'a, V> {
+        let root = NodeIter::Trie(self.root.iter(0));
+
+        Iter {
+            inner: RawIter::new(Vec::new(), vec![root], self.len),
+        }
+    }
+
+    /// Gets an iterator over the entries of the map whose keys fall within `range`, sorted by key.
+    pub fn range<'a, R: KeyRange>(&'a self, range: R) -> Range<'a, V> {
+        let start = range.start_bound();
+        let end = range.end_bound();
+
+        let inverted = match (start, end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end)) |
+            (Bound::Excluded(start), Bound::Included(end)) |
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+
+        let mut inner = RawIter::new(Vec::new(), Vec::new(), self.len);
+
+        // Seek both ends of the walk to the bounds of the range. This skips every subtree that
+        // falls outside of it.
+        if !inverted {
+            self.root.seek(start, end, Side::Shared, &mut inner);
+        }
+
+        Range { inner: inner }
+    }
+
+    /// Gets a mutable iterator over the entries of the map, sorted by key.
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a, V> {
+        let root = NodeIter::Trie(self.root.iter_mut(0));
+
+        IterMut {
+            inner: RawIter::new(Vec::new(), vec![root], self.len),
+        }
+    }
+
+    /// Gets an iterator over the entries of the map whose keys start with `prefix`, sorted by
+    /// key.
+    pub fn iter_prefix<'a>(&'a self, prefix: &[u8]) -> IterPrefix<'a, V> {
+        let mut key = Vec::new();
+
+        let stack = match self.root.find_prefix(prefix, &mut key) {
+            Some(iter) => vec![iter],
+            None => vec![],
+        };
+
+        IterPrefix {
+            inner: RawIter::new(key, stack, self.len),
+        }
+    }
+
+    /// Gets an iterator over the keys of the map, in sorted order.
+    pub fn keys<'a>(&'a self) -> Keys<'a, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Gets an iterator over the values of the map, in order by key.
+    pub fn values<'a>(&'a self) -> Values<'a, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Gets a mutable iterator over the values of the map, in order by key.
+    pub fn values_mut<'a>(&'a mut self) -> ValuesMut<'a, V> {
+        ValuesMut { inner: self.iter_mut() }
+    }
+
+    /// Removes all the entries from the map, and returns them as an iterator sorted by key.
+    pub fn drain(&mut self) -> IntoIter<V> {
+        self.take().into_iter()
+    }
+
+    /// Splits the map in two at `key`. Every entry whose key is greater than or equal to `key` is
+    /// moved into the returned map. Whole subtrees are moved, so only the nodes along `key` are
+    /// rebuilt.
+    pub fn split_off(&mut self, key: &[u8]) -> TrieMap<V> {
+        match self.root.split_off(key, &mut self.observer) {
+            Some(root) => {
+                self.len -= root.count;
+                self.debug_validate();
+
+                let map = TrieMap {
+                    len: root.count,
+                    root: root,
+                    observer: (),
+                };
+
+                map.debug_validate();
+                map
             }
-            Node::Prefix(ref mut node) => {
-                match node.insert(key, value) {
-                    InsertResult::Ok(value) => { return value; }
-                    InsertResult::Burst(trie) => trie,
-                }
+            None => TrieMap::new(),
+        }
+    }
+
+    /// Moves every entry whose key starts with `prefix` into the returned map. This detaches the
+    /// subtree under `prefix` from the trie as a whole.
+    pub fn split_off_prefix(&mut self, prefix: &[u8]) -> TrieMap<V> {
+        if prefix.is_empty() {
+            return self.take();
+        }
+
+        let mut path = Vec::new();
+
+        match self.root.split_off_prefix(prefix, &mut path, &mut self.observer) {
+            Some(node) => {
+                self.len -= node.count();
+
+                let mut map = TrieMap::new();
+                map.len = node.count();
+
+                // Hang the subtree back under the path it was found at.
+                let node = if path.len() > 1 {
+                    Node::from(PrefixNode::from_path(&path[1..], node))
+                } else {
+                    node
+                };
+
+                map.root.insert_child(path[0], node);
+
+                self.debug_validate();
+                map.debug_validate();
+                map
             }
-        };
+            None => TrieMap::new(),
+        }
+    }
 
-        *self = Node::Trie(trie);
+    /// Removes every entry whose key starts with `prefix`, and returns how many were removed.
+    pub fn remove_prefix(&mut self, prefix: &[u8]) -> usize {
+        if prefix.is_empty() {
+            return self.take().len();
+        }
 
-        None
+        let mut path = Vec::new();
+
+        match self.root.split_off_prefix(prefix, &mut path, &mut self.observer) {
+            Some(node) => {
+                let count = node.count();
+                self.len -= count;
+                self.debug_validate();
+                count
+            }
+            None => 0,
+        }
     }
 
-    fn get<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
-        println!("Node.get: {:?}", key);
+    /// Retains only the entries for which `f` returns true, visiting them in order by key.
+    pub fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&[u8], &mut V) -> bool,
+    {
+        let mut key = Vec::new();
 
-        match *self {
-            Node::Trie(ref node) => node.get(key),
-            Node::Prefix(ref node) => node.get(key),
+        self.root.retain(&mut key, &mut f, &mut self.observer);
+        self.len = self.root.count;
+
+        self.debug_validate();
+    }
+
+    /// Filters the map a subtree at a time. `f` is called with a prefix shared by every key in a
+    /// subtree, starting with the empty prefix, and decides whether to keep the subtree, drop it,
+    /// or to descend into it and be asked about each of its branches. Descending keeps the entry
+    /// whose key is the prefix itself, if there is one.
+    pub fn retain_prefixes<F>(&mut self, mut f: F)
+        where F: FnMut(&[u8]) -> Retain,
+    {
+        let mut key = Vec::new();
+
+        match f(&key) {
+            Retain::Keep => { }
+            Retain::Drop => { self.root = TrieNode::empty(); }
+            Retain::Descend => self.root.retain_prefixes(&mut key, &mut f, &mut self.observer),
         }
+
+        self.len = self.root.count;
+
+        self.debug_validate();
     }
 
-    fn len(&self) -> usize {
-        match *self {
-            Node::Trie(ref node) => node.len(),
-            Node::Prefix(ref node) => node.len(),
+    /// Moves all the entries from `other` into the map, leaving `other` empty. If a key is in both
+    /// maps, the value from `other` replaces ours.
+    pub fn append<P: TrieObserver>(&mut self, other: &mut TrieMap<V, P>) {
+        let other = other.take();
+        self.merge_with(other, |_, _, value| value);
+    }
+
+    /// Merges `other` into the map. If a key is in both maps, `f` is called with the key, our
+    /// value and the value from `other`, and its result is stored.
+    ///
+    /// Both tries are walked together, and any subtree that only one of them has is moved over as
+    /// a whole.
+    pub fn merge_with<P, F>(&mut self, other: TrieMap<V, P>, mut f: F)
+        where F: FnMut(&[u8], V, V) -> V,
+    {
+        let root = mem::replace(&mut self.root, TrieNode::empty());
+        let mut key = Vec::new();
+
+        self.root = root.merge(other.root, &mut key, &mut f, &mut self.observer);
+        self.len = self.root.count;
+
+        self.debug_validate();
+    }
+
+    /// Checks that the trie is in the shape the rest of the map relies on:
+    ///
+    /// * The length of the map, and the count kept in every node, match the values stored.
+    /// * Every trie node other than the root has at least two children.
+    /// * Every prefix node holds a value or has a child, and so an empty compressed key always
+    ///   comes with a value.
+    /// * A prefix node without a value has no prefix node child, which should have been merged
+    ///   into it.
+    /// * A prefix node with an empty compressed key has no trie node child, which should have
+    ///   taken its place.
+    /// * A trie node child of a prefix node has no value, which belongs to the prefix node.
+    /// * A prefix node child of a prefix node has a non-empty compressed key.
+    ///
+    /// The byte leading to a trie node's child is only stored as the key of its `children`, so
+    /// there is nothing else for it to disagree with.
+    pub fn validate(&self) -> Result<(), InvariantError> {
+        let mut key = Vec::new();
+        let count = self.root.validate(&mut key, true)?;
+
+        if count != self.len {
+            return Err(InvariantError::Len(self.len, count));
         }
+
+        Ok(())
     }
 
-    fn is_empty(&self) -> bool {
-        self.len() == 0
+    /// Panics if the trie is not valid, when invariant checks are enabled.
+    fn debug_validate(&self) {
+        check_invariants(|| self.validate());
     }
 
-    /*
-    fn iter(&'a self, key: Vec<u8>) -> NodeIter<'a, V> {
-        match *self {
-            Trie(node) => Iter::Trie(node.iter(key)),
-            Prefix(node) => Iter::Prefix(node.iter(key)),
+    /// Moves all the entries into a new map, leaving the observer behind.
+    fn take(&mut self) -> TrieMap<V> {
+        let len = mem::replace(&mut self.len, 0);
+
+        TrieMap {
+            root: mem::replace(&mut self.root, TrieNode::empty()),
+            len: len,
+            observer: (),
         }
     }
-    */
 }
 
-/*
-enum NodeIter<'a, V> {
-    Trie(TrieIter<'a, V>),
-    Prefix(PrefixIter<'a, V>),
+impl<V: Debug, O: TrieObserver> TrieMap<V, O> {
+    /// Renders the nodes of the trie as a Graphviz graph.
+    ///
+    /// Trie nodes are drawn as circles and prefix nodes as boxes labelled with their compressed
+    /// key. Every edge is labelled with the bytes consumed between its two nodes: the byte of the
+    /// child for an edge out of a trie node, and the compressed key for an edge out of a prefix
+    /// node.
+    /// Nodes that hold a value have a double border, and show the value in their label.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph trie {\n");
+        let mut next_id = 0;
+
+        NodeRef::Trie(&self.root).write_dot(&mut out, &mut next_id);
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// Returns a value that formats the nodes of the trie as an ASCII tree, one node per line.
+    pub fn tree<'a>(&'a self) -> Tree<'a, V> {
+        Tree { root: &self.root }
+    }
 }
 
-impl<'a, V> Iterator for Iter<'a, V> {
-    type Item = (Vec<u8>, &'a V);
+impl<V, O: TrieObserver + Default> Default for TrieMap<V, O> {
+    fn default() -> Self {
+        TrieMap::with_observer(O::default())
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match *self {
-            NodeIter::Trie(iter) => iter.next(),
-            NodeIter::Prefix(iter) => iter.next(),
-        }
+impl<V, O: TrieObserver> Index<&[u8]> for TrieMap<V, O> {
+    type Output = V;
+
+    /// Returns a reference to the value for `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is not in the map.
+    fn index(&self, key: &[u8]) -> &V {
+        self.get(key).expect("no entry found for key")
     }
 }
-*/
 
-impl<V: Debug> From<TrieNode<V>> for Node<V> {
-    fn from(node: TrieNode<V>) -> Self {
-        Node::Trie(node)
+impl<V: PartialEq, O: TrieObserver> PartialEq for TrieMap<V, O> {
+    fn eq(&self, other: &TrieMap<V, O>) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
     }
 }
 
-impl<V: Debug> From<PrefixNode<V>> for Node<V> {
-    fn from(node: PrefixNode<V>) -> Self {
-        Node::Prefix(node)
+impl<V: Eq, O: TrieObserver> Eq for TrieMap<V, O> {}
+
+impl<V: PartialOrd, O: TrieObserver> PartialOrd for TrieMap<V, O> {
+    fn partial_cmp(&self, other: &TrieMap<V, O>) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
     }
 }
 
-#[derive(Clone, Debug)]
-struct TrieNode<V> {
-    children: BTreeMap<u8, Box<Node<V>>>,
-    value: Option<V>,
+impl<V: Ord, O: TrieObserver> Ord for TrieMap<V, O> {
+    fn cmp(&self, other: &TrieMap<V, O>) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
 }
 
-impl<V: Debug> TrieNode<V> {
-    fn empty() -> Self {
-        TrieNode {
-            children: BTreeMap::new(),
-            value: None,
+impl<V: Hash, O: TrieObserver> Hash for TrieMap<V, O> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for entry in self.iter() {
+            entry.hash(state);
+        }
+    }
+}
+
+impl<V, O: TrieObserver> IntoIterator for TrieMap<V, O> {
+    type Item = (Vec<u8>, V);
+    type IntoIter = IntoIter<V>;
+
+    fn into_iter(self) -> IntoIter<V> {
+        let root = NodeIter::Trie(self.root.into_iter(0));
+
+        IntoIter {
+            inner: RawIter::new(Vec::new(), vec![root], self.len),
+        }
+    }
+}
+
+impl<'a, V, O: TrieObserver> IntoIterator for &'a TrieMap<V, O> {
+    type Item = (Vec<u8>, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<'a, V, O: TrieObserver> IntoIterator for &'a mut TrieMap<V, O> {
+    type Item = (Vec<u8>, &'a mut V);
+    type IntoIter = IterMut<'a, V>;
+
+    fn into_iter(self) -> IterMut<'a, V> {
+        self.iter_mut()
+    }
+}
+
+/// A depth first walk over the nodes of a trie, shared by all the map iterators, which can be
+/// driven from either end.
+///
+/// It keeps three stacks of partially visited nodes. `shared` holds the nodes that both ends of
+/// the walk still have to visit, from the root down. Below the deepest of those, `front` and
+/// `back` hold the nodes that only the front or the back of the walk has descended into, along
+/// with the key of the deepest of them. When one end runs out of nodes under the deepest shared
+/// node, whatever is left under it belongs to the other end's topmost node, which then becomes
+/// shared.
+///
+/// `remaining` is an upper bound on the number of entries left to yield, which is exact when the
+/// walk started from the root.
+struct RawIter<H: IterHandle> {
+    shared: Vec<NodeIter<H>>,
+    front: Vec<NodeIter<H>>,
+    front_key: Vec<u8>,
+    back: Vec<NodeIter<H>>,
+    back_key: Vec<u8>,
+    remaining: usize,
+}
+
+/// Which of the stacks of a `RawIter` a node belongs to.
+#[derive(Clone, Copy)]
+enum Side {
+    Shared,
+    Front,
+    Back,
+}
+
+impl<H: IterHandle> RawIter<H> {
+    fn new(key: Vec<u8>, shared: Vec<NodeIter<H>>, remaining: usize) -> Self {
+        RawIter {
+            shared: shared,
+            front: Vec::new(),
+            front_key: key.clone(),
+            back: Vec::new(),
+            back_key: key,
+            remaining: remaining,
+        }
+    }
+
+    fn key_len(&self, side: Side) -> usize {
+        match side {
+            Side::Shared | Side::Front => self.front_key.len(),
+            Side::Back => self.back_key.len(),
+        }
+    }
+
+    fn extend_key(&mut self, side: Side, bytes: &[u8]) {
+        match side {
+            Side::Shared => {
+                self.front_key.extend_from_slice(bytes);
+                self.back_key.extend_from_slice(bytes);
+            }
+            Side::Front => self.front_key.extend_from_slice(bytes),
+            Side::Back => self.back_key.extend_from_slice(bytes),
+        }
+    }
+
+    fn push(&mut self, side: Side, iter: NodeIter<H>) {
+        match side {
+            Side::Shared => self.shared.push(iter),
+            Side::Front => self.front.push(iter),
+            Side::Back => self.back.push(iter),
+        }
+    }
+}
+
+impl<H: IterHandle> Iterator for RawIter<H> {
+    type Item = (Vec<u8>, H::Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Either yield the value of the deepest node, or find the next child node to descend
+            // into. When the node is exhausted, pop it off the stack.
+            let step = {
+                let iter = match self.front.last_mut() {
+                    Some(iter) => iter,
+                    None => {
+                        match self.shared.last_mut() {
+                            Some(iter) => iter,
+                            None => { return None; }
+                        }
+                    }
+                };
+
+                self.front_key.truncate(iter.key_len());
+                iter.next()
+            };
+
+            match step {
+                Step::Value(value) => {
+                    self.remaining -= 1;
+                    return Some((self.front_key.clone(), value));
+                }
+                Step::Child(byte, child) => {
+                    self.front_key.extend(byte);
+                    let iter = child.open(&mut self.front_key);
+                    self.front.push(iter);
+                }
+                Step::Done => {
+                    if self.front.pop().is_none() {
+                        if self.back.is_empty() {
+                            self.shared.pop();
+                        } else {
+                            let iter = self.back.remove(0);
+                            self.front_key.clear();
+                            self.front_key.extend_from_slice(&self.back_key[..iter.key_len()]);
+                            self.shared.push(iter);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<H: IterHandle> DoubleEndedIterator for RawIter<H> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            // Either descend into the last child of the deepest node, or yield its value once all
+            // the children are done. When the node is exhausted, pop it off the stack.
+            let step = {
+                let iter = match self.back.last_mut() {
+                    Some(iter) => iter,
+                    None => {
+                        match self.shared.last_mut() {
+                            Some(iter) => iter,
+                            None => { return None; }
+                        }
+                    }
+                };
+
+                self.back_key.truncate(iter.key_len());
+                iter.next_back()
+            };
+
+            match step {
+                Step::Value(value) => {
+                    self.remaining -= 1;
+                    return Some((self.back_key.clone(), value));
+                }
+                Step::Child(byte, child) => {
+                    self.back_key.extend(byte);
+                    let iter = child.open(&mut self.back_key);
+                    self.back.push(iter);
+                }
+                Step::Done => {
+                    if self.back.pop().is_none() {
+                        if self.front.is_empty() {
+                            self.shared.pop();
+                        } else {
+                            let iter = self.front.remove(0);
+                            self.back_key.clear();
+                            self.back_key.extend_from_slice(&self.front_key[..iter.key_len()]);
+                            self.shared.push(iter);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over the entries of a `TrieMap`, sorted by key.
+pub struct Iter<'a, V: 'a> {
+    inner: RawIter<&'a Node<V>>,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (Vec<u8>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, V> ExactSizeIterator for Iter<'a, V> {}
+
+impl<'a, V> DoubleEndedIterator for Iter<'a, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// An iterator over the entries of a `TrieMap` that start with a prefix, sorted by key.
+pub struct IterPrefix<'a, V: 'a> {
+    inner: RawIter<&'a Node<V>>,
+}
+
+impl<'a, V> Iterator for IterPrefix<'a, V> {
+    type Item = (Vec<u8>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (_, upper) = self.inner.size_hint();
+        (0, upper)
+    }
+}
+
+impl<'a, V> DoubleEndedIterator for IterPrefix<'a, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// A range of keys that `TrieMap::range` can iterate over.
+///
+/// This is implemented for every standard range whose ends are byte strings, such as `a..b`,
+/// `a..=b`, `a..`, `..b` and `..`, as well as for a pair of `Bound`s.
+pub trait KeyRange {
+    /// The lower bound of the range.
+    fn start_bound(&self) -> Bound<&[u8]>;
+
+    /// The upper bound of the range.
+    fn end_bound(&self) -> Bound<&[u8]>;
+}
+
+fn key_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+impl KeyRange for ops::RangeFull {
+    fn start_bound(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+
+    fn end_bound(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::Range<K> {
+    fn start_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::start_bound(self))
+    }
+
+    fn end_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::end_bound(self))
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::RangeInclusive<K> {
+    fn start_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::start_bound(self))
+    }
+
+    fn end_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::end_bound(self))
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::RangeFrom<K> {
+    fn start_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::start_bound(self))
+    }
+
+    fn end_bound(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::RangeTo<K> {
+    fn start_bound(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+
+    fn end_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::end_bound(self))
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::RangeToInclusive<K> {
+    fn start_bound(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+
+    fn end_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::end_bound(self))
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for (Bound<K>, Bound<K>) {
+    fn start_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::start_bound(self))
+    }
+
+    fn end_bound(&self) -> Bound<&[u8]> {
+        key_bound(RangeBounds::end_bound(self))
+    }
+}
+
+/// An iterator over a sub-range of the entries of a `TrieMap`, sorted by key.
+pub struct Range<'a, V: 'a> {
+    inner: RawIter<&'a Node<V>>,
+}
+
+impl<'a, V> Iterator for Range<'a, V> {
+    type Item = (Vec<u8>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (_, upper) = self.inner.size_hint();
+        (0, upper)
+    }
+}
+
+impl<'a, V> DoubleEndedIterator for Range<'a, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// An iterator over the keys in a `TrieMap` that are prefixes of some input, from shortest to
+/// longest.
+pub struct CommonPrefixes<'a, 'k, V: 'a> {
+    node: Option<NodeRef<'a, V>>,
+    key: &'k [u8],
+    pos: usize,
+}
+
+impl<'a, 'k, V> Iterator for CommonPrefixes<'a, 'k, V> {
+    type Item = (usize, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = match self.node.take() {
+                Some(node) => node,
+                None => { return None; }
+            };
+
+            let key = &self.key[self.pos..];
+
+            let value = match node {
+                NodeRef::Trie(node) => {
+                    let value = node.value.as_ref().map(|value| (self.pos, value));
+
+                    if let Some(byte) = key.first() {
+                        if let Some(child) = node.children.get(byte) {
+                            self.node = Some(NodeRef::new(child));
+                            self.pos += 1;
+                        }
+                    }
+
+                    value
+                }
+                NodeRef::Prefix(node) => {
+                    if !key.starts_with(&node.key) {
+                        return None;
+                    }
+
+                    self.pos += node.key.len();
+
+                    if let Some(ref child) = node.child {
+                        self.node = Some(NodeRef::new(child));
+                    }
+
+                    node.value.as_ref().map(|value| (self.pos, value))
+                }
+            };
+
+            if value.is_some() {
+                return value;
+            }
+        }
+    }
+}
+
+/// A position in a `TrieMap` that can be moved down the trie one byte at a time.
+///
+/// The position may fall in the middle of the key of a compressed node, in which case `offset`
+/// is how much of that key has been consumed.
+pub struct Cursor<'a, V: 'a> {
+    root: &'a TrieNode<V>,
+    node: NodeRef<'a, V>,
+    offset: usize,
+    depth: usize,
+}
+
+impl<'a, V> Cursor<'a, V> {
+    /// Moves the cursor down along `byte`. Returns false, and leaves the cursor where it was, if
+    /// no key in the map continues with `byte`.
+    pub fn step(&mut self, byte: u8) -> bool {
+        match self.node.step(self.offset, byte) {
+            Some((node, offset)) => {
+                self.node = node;
+                self.offset = offset;
+                self.depth += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the cursor down along every byte of `bytes`. Returns false, and leaves the cursor
+    /// where it was, if no key in the map continues with all of them.
+    pub fn step_many(&mut self, bytes: &[u8]) -> bool {
+        let (node, offset, depth) = (self.node, self.offset, self.depth);
+
+        for byte in bytes {
+            if !self.step(*byte) {
+                self.node = node;
+                self.offset = offset;
+                self.depth = depth;
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns the value stored at the key the cursor is at, if there is one.
+    pub fn value(&self) -> Option<&'a V> {
+        match self.node {
+            NodeRef::Trie(node) => node.value.as_ref(),
+            NodeRef::Prefix(node) => {
+                if self.offset == node.key.len() {
+                    node.value.as_ref()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Returns the number of bytes the cursor has moved down from the root.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Gets an iterator over the bytes that the cursor can step along, in sorted order.
+    pub fn next_bytes(&self) -> NextBytes<'a, V> {
+        let mut node = self.node;
+        let mut offset = self.offset;
+
+        loop {
+            match node {
+                NodeRef::Trie(trie) => {
+                    return NextBytes {
+                        byte: None,
+                        children: Some(trie.children.keys()),
+                    };
+                }
+                NodeRef::Prefix(prefix) => {
+                    if offset < prefix.key.len() {
+                        return NextBytes {
+                            byte: Some(prefix.key[offset]),
+                            children: None,
+                        };
+                    }
+
+                    match prefix.child {
+                        Some(ref child) => {
+                            node = NodeRef::new(child);
+                            offset = 0;
+                        }
+                        None => {
+                            return NextBytes {
+                                byte: None,
+                                children: None,
+                            };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the number of keys at or below the cursor.
+    pub(crate) fn count(&self) -> usize {
+        match self.node {
+            NodeRef::Trie(node) => node.count,
+            NodeRef::Prefix(node) => node.count,
+        }
+    }
+
+    /// Gets an iterator over the entries at or below the cursor, sorted by key. `key` must be the
+    /// key that leads to the cursor.
+    pub(crate) fn iter_below(&self, mut key: Vec<u8>) -> IterPrefix<'a, V> {
+        let (iter, count) = match self.node {
+            NodeRef::Trie(node) => (NodeIter::Trie(node.iter(key.len())), node.count),
+            NodeRef::Prefix(node) => {
+                key.extend_from_slice(&node.key[self.offset..]);
+                (NodeIter::Prefix(node.iter(key.len())), node.count)
+            }
+        };
+
+        IterPrefix {
+            inner: RawIter::new(key, vec![iter], count),
+        }
+    }
+
+    /// Moves the cursor back to the root of the map.
+    pub fn reset(&mut self) {
+        self.node = NodeRef::Trie(self.root);
+        self.offset = 0;
+        self.depth = 0;
+    }
+}
+
+impl<'a, V> Clone for Cursor<'a, V> {
+    fn clone(&self) -> Self {
+        Cursor {
+            root: self.root,
+            node: self.node,
+            offset: self.offset,
+            depth: self.depth,
+        }
+    }
+}
+
+/// An iterator over the bytes that can follow the position of a `Cursor`.
+pub struct NextBytes<'a, V: 'a> {
+    byte: Option<u8>,
+    children: Option<btree_map::Keys<'a, u8, Box<Node<V>>>>,
+}
+
+impl<'a, V> Iterator for NextBytes<'a, V> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if let Some(byte) = self.byte.take() {
+            return Some(byte);
+        }
+
+        match self.children {
+            Some(ref mut children) => children.next().cloned(),
+            None => None,
+        }
+    }
+}
+
+/// The path a lookup took through the trie. Created by `TrieMap::explain`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LookupTrace {
+    /// The nodes that were visited, starting at the root.
+    pub steps: Vec<TraceStep>,
+    /// The number of bytes of the key that were matched.
+    pub matched: usize,
+    /// Why the lookup stopped at the last node.
+    pub stop: StopReason,
+}
+
+/// A node visited by a lookup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    pub kind: NodeKind,
+    /// The number of bytes of the key that were consumed before reaching this node.
+    pub depth: usize,
+    /// The number of bytes of the key that this node consumed. A trie node consumes the byte that
+    /// leads to its child, and a prefix node consumes as much of its key as matched.
+    pub consumed: usize,
+    /// The length of a prefix node's compressed key, or 0 for a trie node.
+    pub key_len: usize,
+}
+
+/// Why a lookup stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The key is in the map.
+    Found,
+    /// The last node has no child for the next byte of the key.
+    MissingChild(u8),
+    /// The key differs from the last node's compressed key at this offset into it.
+    Diverged(usize),
+    /// The key ran out, either at a node that holds no value, or partway through the last node's
+    /// compressed key.
+    NoValue,
+}
+
+/// How a key that is not in the map relates to the keys that are. Returned by
+/// `TrieMap::get_or_mismatch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The length of the longest path through the trie that the key matched.
+    pub matched: usize,
+    pub kind: MismatchKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// The key is a proper prefix of one or more stored keys.
+    Prefix,
+    /// The key goes on past a stored key, and this is the length of the longest such key.
+    Extends(usize),
+    /// The key neither is a prefix of a stored key, nor has one as a prefix.
+    Diverged,
+}
+
+/// How a key relates to the keys in a map. Returned by `TrieMap::classify`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyRelation {
+    /// The key is in the map.
+    Stored,
+    /// The key is a proper prefix of one or more keys in the map.
+    Prefix,
+    /// The key goes on past a key in the map.
+    Extends,
+    /// The key neither is a prefix of a key in the map, nor has one as a prefix.
+    Unrelated,
+}
+
+/// A way in which a trie is broken. Returned by `TrieMap::validate`, along with the key of the
+/// node where it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantError {
+    /// The length of the map is not the number of values it holds, which is the second field.
+    Len(usize, usize),
+    /// The count kept by a node is not the number of values below it.
+    Count(Vec<u8>),
+    /// A trie node other than the root has fewer than two children.
+    SparseTrie(Vec<u8>),
+    /// A prefix node has neither a value nor a child.
+    EmptyLeaf(Vec<u8>),
+    /// A prefix node has an empty compressed key and a trie node child.
+    EmptyPrefix(Vec<u8>),
+    /// A prefix node has a trie node child with a value.
+    TrieChildValue(Vec<u8>),
+    /// A prefix node without a value has a prefix node child.
+    UnmergedPrefix(Vec<u8>),
+    /// A prefix node has a prefix node child with an empty compressed key.
+    EmptyPrefixChild(Vec<u8>),
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvariantError::Len(len, count) => {
+                write!(f, "the map has a length of {}, but holds {} values", len, count)
+            }
+            InvariantError::Count(ref key) => {
+                write!(f, "the count of the node at \"{}\" is wrong", escape(key))
+            }
+            InvariantError::SparseTrie(ref key) => {
+                write!(f, "the trie node at \"{}\" has fewer than two children", escape(key))
+            }
+            InvariantError::EmptyLeaf(ref key) => {
+                write!(f, "the prefix node at \"{}\" has no value or child", escape(key))
+            }
+            InvariantError::EmptyPrefix(ref key) => {
+                write!(f,
+                       "the prefix node at \"{}\" has an empty key and a trie node child",
+                       escape(key))
+            }
+            InvariantError::TrieChildValue(ref key) => {
+                write!(f,
+                       "the prefix node at \"{}\" has a trie node child with a value",
+                       escape(key))
+            }
+            InvariantError::UnmergedPrefix(ref key) => {
+                write!(f,
+                       "the prefix node at \"{}\" has no value, and a prefix node child",
+                       escape(key))
+            }
+            InvariantError::EmptyPrefixChild(ref key) => {
+                write!(f,
+                       "the prefix node at \"{}\" has a prefix node child with an empty key",
+                       escape(key))
+            }
+        }
+    }
+}
+
+impl error::Error for InvariantError {}
+
+/// Panics if `validate` finds a broken invariant. Every check walks the part of the trie it is
+/// given, so they are only run in debug builds with the `check-invariants` feature. The crate's
+/// dev-dependency on itself turns the feature on for all of its tests.
+fn check_invariants<F>(validate: F)
+    where F: FnOnce() -> Result<(), InvariantError>,
+{
+    if cfg!(all(debug_assertions, feature = "check-invariants")) {
+        if let Err(err) = validate() {
+            panic!("invalid trie: {}", err);
+        }
+    }
+}
+
+/// Formats the nodes of a `TrieMap` as an ASCII tree. Created by `TrieMap::tree`.
+///
+/// Each line shows the byte that leads to a trie node's child, the kind of the node, a prefix
+/// node's compressed key, and the value if the node holds one:
+///
+/// ```text
+/// trie
+/// |-- 'a' prefix "b" = 1
+/// |   `-- prefix "c" = 2
+/// `-- 'b' prefix "" = 3
+/// ```
+pub struct Tree<'a, V: 'a> {
+    root: &'a TrieNode<V>,
+}
+
+impl<'a, V: Debug> fmt::Display for Tree<'a, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut indent = String::new();
+        NodeRef::Trie(self.root).fmt_tree(f, None, &mut indent)
+    }
+}
+
+/// A mutable iterator over the entries of a `TrieMap`, sorted by key.
+pub struct IterMut<'a, V: 'a> {
+    inner: RawIter<&'a mut Node<V>>,
+}
+
+impl<'a, V> Iterator for IterMut<'a, V> {
+    type Item = (Vec<u8>, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, V> ExactSizeIterator for IterMut<'a, V> {}
+
+impl<'a, V> DoubleEndedIterator for IterMut<'a, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// An owning iterator over the entries of a `TrieMap`, sorted by key.
+pub struct IntoIter<V> {
+    inner: RawIter<Box<Node<V>>>,
+}
+
+impl<V> Iterator for IntoIter<V> {
+    type Item = (Vec<u8>, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<V> ExactSizeIterator for IntoIter<V> {}
+
+impl<V> DoubleEndedIterator for IntoIter<V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// An iterator over the keys of a `TrieMap`, in sorted order.
+pub struct Keys<'a, V: 'a> {
+    inner: Iter<'a, V>,
+}
+
+impl<'a, V> Iterator for Keys<'a, V> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.inner.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, V> ExactSizeIterator for Keys<'a, V> {}
+
+impl<'a, V> DoubleEndedIterator for Keys<'a, V> {
+    fn next_back(&mut self) -> Option<Vec<u8>> {
+        self.inner.next_back().map(|(key, _)| key)
+    }
+}
+
+/// An iterator over the values of a `TrieMap`, in order by key.
+pub struct Values<'a, V: 'a> {
+    inner: Iter<'a, V>,
+}
+
+impl<'a, V> Iterator for Values<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, V> ExactSizeIterator for Values<'a, V> {}
+
+impl<'a, V> DoubleEndedIterator for Values<'a, V> {
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.inner.next_back().map(|(_, value)| value)
+    }
+}
+
+/// A mutable iterator over the values of a `TrieMap`, in order by key.
+pub struct ValuesMut<'a, V: 'a> {
+    inner: IterMut<'a, V>,
+}
+
+impl<'a, V> Iterator for ValuesMut<'a, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.inner.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, V> ExactSizeIterator for ValuesMut<'a, V> {}
+
+impl<'a, V> DoubleEndedIterator for ValuesMut<'a, V> {
+    fn next_back(&mut self) -> Option<&'a mut V> {
+        self.inner.next_back().map(|(_, value)| value)
+    }
+}
+
+/// What `TrieMap::retain_prefixes` should do with the keys under a prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retain {
+    /// Keep every key under the prefix.
+    Keep,
+    /// Remove every key under the prefix.
+    Drop,
+    /// Keep the key that is the prefix itself, and decide for each branch below it.
+    Descend,
+}
+
+/// A view into a single entry in a `TrieMap`, which may either be vacant or occupied.
+pub enum Entry<'a, V: 'a, O: 'a = ()> {
+    Vacant(VacantEntry<'a, V, O>),
+    Occupied(OccupiedEntry<'a, V, O>),
+}
+
+impl<'a, V, O: TrieObserver> Entry<'a, V, O> {
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &[u8] {
+        match *self {
+            Entry::Vacant(ref entry) => entry.key(),
+            Entry::Occupied(ref entry) => entry.key(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the default if empty, and returns a mutable
+    /// reference to the value in the entry.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default),
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a mutable reference to the value in the entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default()),
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    /// Provides in-place mutable access to an occupied entry before any potential inserts into
+    /// the map.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+        }
+    }
+}
+
+impl<'a, V: Default, O: TrieObserver> Entry<'a, V, O> {
+    /// Ensures a value is in the entry by inserting the default value if empty, and returns a
+    /// mutable reference to the value in the entry.
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(Default::default)
+    }
+}
+
+/// A view into a vacant entry in a `TrieMap`. It holds on to the node where the key diverged from
+/// the trie, so inserting only has to burst that node.
+pub struct VacantEntry<'a, V: 'a, O: 'a = ()> {
+    key: Vec<u8>,
+    pos: usize,
+    node: VacantNode<'a, V>,
+    counts: Vec<&'a mut usize>,
+    len: &'a mut usize,
+    observer: &'a mut O,
+}
+
+impl<'a, V, O: TrieObserver> VacantEntry<'a, V, O> {
+    /// Gets a reference to the key that would be used when inserting a value through the
+    /// `VacantEntry`.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Take ownership of the key.
+    pub fn into_key(self) -> Vec<u8> {
+        self.key
+    }
+
+    /// Sets the value of the entry with the `VacantEntry`'s key, and returns a mutable reference
+    /// to it.
+    pub fn insert(self, value: V) -> &'a mut V {
+        *self.len += 1;
+
+        // The node we insert into keeps its own count up to date, but the nodes above it do not
+        // know about the insert.
+        for count in self.counts {
+            *count += 1;
+        }
+
+        let key = &self.key[self.pos..];
+        let path = &self.key[..self.pos];
+
+        // Only the node that was inserted into can be checked, as the entry cannot reach the rest
+        // of the map.
+        match self.node {
+            VacantNode::Trie(node) => {
+                node.insert(key, value, self.observer);
+                check_invariants(|| node.validate(&mut path.to_vec(), path.is_empty()).map(|_| ()));
+                node.get_mut(key).unwrap()
+            }
+            VacantNode::Node(node) => {
+                node.insert(key, value, self.observer);
+                check_invariants(|| node.validate(&mut path.to_vec()).map(|_| ()));
+                node.get_mut(key).unwrap()
+            }
+        }
+    }
+}
+
+/// A view into an occupied entry in a `TrieMap`.
+pub struct OccupiedEntry<'a, V: 'a, O: 'a = ()> {
+    key: Vec<u8>,
+    value: &'a mut V,
+    observer: &'a mut O,
+}
+
+impl<'a, V, O: TrieObserver> OccupiedEntry<'a, V, O> {
+    /// Gets a reference to the key in the entry.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        self.value
+    }
+
+    /// Gets a mutable reference to the value in the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        self.value
+    }
+
+    /// Converts the entry into a mutable reference to its value.
+    pub fn into_mut(self) -> &'a mut V {
+        self.value
+    }
+
+    /// Sets the value of the entry with the `OccupiedEntry`'s key, and returns the entry's old
+    /// value.
+    pub fn insert(&mut self, value: V) -> V {
+        self.observer.value_replaced(&self.key);
+        mem::replace(self.value, value)
+    }
+}
+
+/// The node a vacant key should be inserted into. The root of the map is not wrapped in a `Node`,
+/// so it is tracked separately.
+enum VacantNode<'a, V: 'a> {
+    Trie(&'a mut TrieNode<V>),
+    Node(&'a mut Node<V>),
+}
+
+enum EntryNode<'a, V: 'a> {
+    Occupied(&'a mut V),
+    Vacant(VacantNode<'a, V>, usize),
+}
+
+/// Where `find_entry` should go next from a node.
+enum EntryStep {
+    Occupied,
+    Vacant,
+    Descend(usize),
+}
+
+impl<'a, V, O: TrieObserver + Default> iter::FromIterator<(&'a [u8], V)> for TrieMap<V, O> {
+    fn from_iter<I: IntoIterator<Item=(&'a [u8], V)>>(iterator: I) -> Self {
+        let mut map = TrieMap::default();
+        for (key, value) in iterator.into_iter() {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<V, O: TrieObserver + Default> iter::FromIterator<(Vec<u8>, V)> for TrieMap<V, O> {
+    fn from_iter<I: IntoIterator<Item=(Vec<u8>, V)>>(iterator: I) -> Self {
+        let mut map = TrieMap::default();
+        map.extend(iterator);
+        map
+    }
+}
+
+impl<'a, V, O: TrieObserver> Extend<(&'a [u8], V)> for TrieMap<V, O> {
+    fn extend<I: IntoIterator<Item=(&'a [u8], V)>>(&mut self, iterator: I) {
+        for (key, value) in iterator.into_iter() {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<V, O: TrieObserver> Extend<(Vec<u8>, V)> for TrieMap<V, O> {
+    fn extend<I: IntoIterator<Item=(Vec<u8>, V)>>(&mut self, iterator: I) {
+        for (key, value) in iterator.into_iter() {
+            self.insert(&key, value);
+        }
+    }
+}
+
+/*
+impl<V> fmt::Debug for TrieMap<V>
+    where V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<V> quickcheck::Arbitrary for TrieMap<V>
+    where V: Clone + quickcheck::Arbitrary,
+{
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> TrieMap<V> {
+        use std::collections::HashMap;
+
+        let items: HashMap<Vec<u8>, V> = quickcheck::Arbitrary::arbitrary(g);
+        TrieMap::from_iter(items.into_iter())
+    }
+
+    fn shrink(&self) -> Box<Iterator<Item=TrieMap<V>>> {
+        use std::collections::HashMap;
+
+        let items: HashMap<Vec<u8>, V> = self.iter()
+            .map(|(key, value)| (key.to_owned(), value.clone()))
+            .collect();
+
+        Box::new(items.shrink()
+            .map(|items| items.into_iter().collect::<TrieMap<V>>()))
+    }
+}
+*/
+
+#[derive(Clone, Debug)]
+enum Node<V> {
+    Trie(TrieNode<V>),
+    Prefix(PrefixNode<V>),
+}
+
+enum InsertResult<V> {
+    Ok(Option<V>),
+    Burst(TrieNode<V>),
+}
+
+impl<V> Node<V> {
+    fn insert<O: TrieObserver>(&mut self, key: &[u8], value: V, observer: &mut O) -> Option<V> {
+        let trie = match *self {
+            Node::Trie(ref mut node) => {
+                return node.insert(key, value, observer);
+            }
+            Node::Prefix(ref mut node) => {
+                match node.insert(key, value, observer) {
+                    InsertResult::Ok(value) => { return value; }
+                    InsertResult::Burst(trie) => trie,
+                }
+            }
+        };
+
+        *self = Node::Trie(trie);
+
+        None
+    }
+
+    fn get<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
+        match *self {
+            Node::Trie(ref node) => node.get(key),
+            Node::Prefix(ref node) => node.get(key),
+        }
+    }
+
+    fn get_mut<'a>(&'a mut self, key: &[u8]) -> Option<&'a mut V> {
+        match *self {
+            Node::Trie(ref mut node) => node.get_mut(key),
+            Node::Prefix(ref mut node) => node.get_mut(key),
+        }
+    }
+
+    /// Walk down to the node that holds `key`, or the node that `key` would need to be inserted
+    /// into. `pos` is the number of bytes of the key that were consumed to reach this node, and
+    /// the entry counts of the nodes passed through on the way are collected into `counts`.
+    fn find_entry<'a>(&'a mut self,
+                      key: &[u8],
+                      pos: usize,
+                      counts: &mut Vec<&'a mut usize>) -> EntryNode<'a, V> {
+        let step = match *self {
+            Node::Trie(ref node) => node.entry_step(key),
+            Node::Prefix(ref node) => node.entry_step(key),
+        };
+
+        match step {
+            EntryStep::Occupied => {
+                let value = match *self {
+                    Node::Trie(ref mut node) => node.value.as_mut(),
+                    Node::Prefix(ref mut node) => node.value.as_mut(),
+                };
+                EntryNode::Occupied(value.unwrap())
+            }
+            EntryStep::Vacant => EntryNode::Vacant(VacantNode::Node(self), pos),
+            EntryStep::Descend(n) => {
+                let (count, child) = match *self {
+                    Node::Trie(ref mut node) => (&mut node.count, node.children.get_mut(&key[0])),
+                    Node::Prefix(ref mut node) => (&mut node.count, node.child.as_mut()),
+                };
+                counts.push(count);
+                child.unwrap().find_entry(&key[n..], pos + n, counts)
+            }
+        }
+    }
+
+    /// The number of keys that are strictly less than `key`.
+    fn rank(&self, key: &[u8]) -> usize {
+        match *self {
+            Node::Trie(ref node) => node.rank(key),
+            Node::Prefix(ref node) => node.rank(key),
+        }
+    }
+
+    /// Find the `n`th value under this node, and extend `key` with its key. `n` must be less than
+    /// the node's count.
+    fn nth<'a>(&'a self, n: usize, key: &mut Vec<u8>) -> &'a V {
+        match *self {
+            Node::Trie(ref node) => node.nth(n, key),
+            Node::Prefix(ref node) => node.nth(n, key),
+        }
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> usize {
+        match *self {
+            Node::Trie(ref node) => node.count_prefix(prefix),
+            Node::Prefix(ref node) => node.count_prefix(prefix),
+        }
+    }
+
+    /// Find the node that covers every key starting with `prefix`, and start iterating over it.
+    /// `key` is extended with the key of that node.
+    fn find_prefix<'a>(&'a self, prefix: &[u8], key: &mut Vec<u8>) -> Option<NodeIter<&'a Node<V>>> {
+        match *self {
+            Node::Trie(ref node) => node.find_prefix(prefix, key),
+            Node::Prefix(ref node) => node.find_prefix(prefix, key),
+        }
+    }
+
+    /// Push the nodes that hold the keys between `start` and `end` onto the stacks of `iter`, so
+    /// that walking it only visits the keys in that range. `side` is the stack this node belongs
+    /// to.
+    fn seek<'a>(&'a self,
+                start: Bound<&[u8]>,
+                end: Bound<&[u8]>,
+                side: Side,
+                iter: &mut RawIter<&'a Node<V>>) {
+        match *self {
+            Node::Trie(ref node) => node.seek(start, end, side, iter),
+            Node::Prefix(ref node) => node.seek(start, end, side, iter),
+        }
+    }
+
+    /// Remove the key from this node, and compact the node if that left it in a shape that
+    /// `insert` would not have built.
+    fn remove<O: TrieObserver>(&mut self, key: &[u8], observer: &mut O) -> Option<V> {
+        let value = match *self {
+            Node::Trie(ref mut node) => node.remove(key, observer),
+            Node::Prefix(ref mut node) => node.remove(key, observer),
+        };
+
+        if value.is_some() {
+            self.compact(observer);
+        }
+
+        value
+    }
+
+    /// Split off every key under this node that is greater than or equal to `key` into a new node
+    /// at the same position in the trie. Both this node and the new one are compacted.
+    fn split_off<O: TrieObserver>(&mut self, key: &[u8], observer: &mut O) -> Option<Node<V>> {
+        let right = match *self {
+            Node::Trie(ref mut node) => node.split_off(key, observer).map(Node::from),
+            Node::Prefix(ref mut node) => node.split_off(key, observer).map(Node::from),
+        };
+
+        if right.is_some() {
+            self.compact(observer);
+        }
+
+        // The split off node goes to a new map, which does not share our observer.
+        right.map(|right| right.into_compact(&mut ()))
+    }
+
+    /// Detach the node below this one that holds every key starting with `prefix`, which must not
+    /// be empty. `path` is extended with the key of the detached node.
+    fn split_off_prefix<O: TrieObserver>(&mut self,
+                                         prefix: &[u8],
+                                         path: &mut Vec<u8>,
+                                         observer: &mut O) -> Option<Node<V>> {
+        let node = match *self {
+            Node::Trie(ref mut node) => node.split_off_prefix(prefix, path, observer),
+            Node::Prefix(ref mut node) => node.split_off_prefix(prefix, path, observer),
+        };
+
+        if node.is_some() {
+            self.compact(observer);
+        }
+
+        node
+    }
+
+    /// Merge two nodes that sit at the same position in the trie. `key` is the key of that
+    /// position, and `f` combines the values that are in both nodes.
+    fn merge<F, O>(self,
+                   other: Node<V>,
+                   key: &mut Vec<u8>,
+                   f: &mut F,
+                   observer: &mut O) -> Node<V>
+        where F: FnMut(&[u8], V, V) -> V,
+              O: TrieObserver,
+    {
+        let (lhs, rhs) = match (self, other) {
+            (Node::Prefix(mut lhs), Node::Prefix(mut rhs)) => {
+                let len = lhs.key.iter()
+                    .zip(rhs.key.iter())
+                    .take_while(|&(lhs, rhs)| lhs == rhs)
+                    .count();
+
+                // Merge the shared part of the keys in one step, rather than a byte at a time.
+                if len > 0 {
+                    let common: Vec<u8> = lhs.key.drain(..len).collect();
+                    rhs.key.drain(..len);
+
+                    key.extend_from_slice(&common);
+                    let child = Node::from(lhs).merge(Node::from(rhs), key, f, observer);
+                    let key_len = key.len();
+                    key.truncate(key_len - len);
+
+                    observer.node_created(NodeKind::Prefix);
+
+                    return PrefixNode::with_child(common, None, Some(Box::new(child)))
+                        .into_compact(observer);
+                }
+
+                (Node::from(lhs), Node::from(rhs))
+            }
+            nodes => nodes,
+        };
+
+        let lhs = lhs.into_trie(observer);
+        let rhs = rhs.into_trie(observer);
+
+        let trie = lhs.merge(rhs, key, f, observer);
+        Node::from(trie).into_compact(observer)
+    }
+
+    /// Convert this node into a trie node holding the same keys.
+    fn into_trie<O: TrieObserver>(self, observer: &mut O) -> TrieNode<V> {
+        match self {
+            Node::Trie(node) => node,
+            Node::Prefix(mut node) => node.burst_into_trie(observer),
+        }
+    }
+
+    /// Check the shape of this node and everything below it, and return the number of values it
+    /// holds. `key` holds the key of this node.
+    fn validate(&self, key: &mut Vec<u8>) -> Result<usize, InvariantError> {
+        match *self {
+            Node::Trie(ref node) => node.validate(key, false),
+            Node::Prefix(ref node) => node.validate(key),
+        }
+    }
+
+    /// Returns true if every key under this node starts with `prefix`.
+    fn covers(&self, prefix: &[u8]) -> bool {
+        match *self {
+            Node::Trie(_) => prefix.is_empty(),
+            Node::Prefix(ref node) => node.key.starts_with(prefix),
+        }
+    }
+
+    /// Remove the values under this node that `f` rejects. `key` holds the key of this node.
+    fn retain<F, O>(&mut self, key: &mut Vec<u8>, f: &mut F, observer: &mut O)
+        where F: FnMut(&[u8], &mut V) -> bool,
+              O: TrieObserver,
+    {
+        match *self {
+            Node::Trie(ref mut node) => node.retain(key, f, observer),
+            Node::Prefix(ref mut node) => node.retain(key, f, observer),
+        }
+
+        self.compact(observer);
+    }
+
+    /// Ask `f` what to do with the keys under this node. `key` holds the key of this node.
+    fn retain_prefixes<F, O>(&mut self, key: &mut Vec<u8>, f: &mut F, observer: &mut O)
+        where F: FnMut(&[u8]) -> Retain,
+              O: TrieObserver,
+    {
+        match *self {
+            Node::Trie(ref mut node) => {
+                match f(key) {
+                    Retain::Keep => { }
+                    Retain::Drop => { *node = TrieNode::empty(); }
+                    Retain::Descend => node.retain_prefixes(key, f, observer),
+                }
+            }
+            Node::Prefix(ref mut node) => node.retain_prefixes(key, f, observer),
+        }
+
+        self.compact(observer);
+    }
+
+    /// Rewrite this node in place into its canonical shape.
+    fn compact<O: TrieObserver>(&mut self, observer: &mut O) {
+        let node = mem::replace(self, Node::Trie(TrieNode::empty()));
+        *self = node.into_compact(observer);
+    }
+
+    /// Rewrite this node into the canonical shape for its contents:
+    ///
+    /// * A trie node below the root has at least two children.
+    /// * A prefix node has a value or a child.
+    /// * A prefix node without a value does not have a prefix node child.
+    /// * A prefix node with an empty key does not have a trie node child.
+    /// * A trie node below a prefix node does not have a value.
+    fn into_compact<O: TrieObserver>(self, observer: &mut O) -> Node<V> {
+        match self {
+            Node::Trie(node) => {
+                if node.children.len() < 2 {
+                    Node::Prefix(node.into_prefix(observer))
+                } else {
+                    Node::Trie(node)
+                }
+            }
+            Node::Prefix(node) => node.into_compact(observer),
+        }
+    }
+
+    /// Returns true if this node holds no values, and so can be pruned from its parent. This is
+    /// only valid on a compacted node.
+    fn is_vacant(&self) -> bool {
+        match *self {
+            Node::Trie(_) => false,
+            Node::Prefix(ref node) => node.value.is_none() && node.child.is_none(),
+        }
+    }
+
+    /// The number of values held in this node and all of its descendants.
+    fn count(&self) -> usize {
+        match *self {
+            Node::Trie(ref node) => node.count,
+            Node::Prefix(ref node) => node.count,
+        }
+    }
+}
+
+/// A reference to, or ownership of, a node that `RawIter` can take apart. This lets the same walk
+/// drive the shared, mutable and owning iterators.
+trait IterHandle: Sized {
+    type Value;
+    type Children: DoubleEndedIterator<Item=(u8, Self)>;
+
+    /// Start iterating over this node. `key` must hold the key of this node's parent, and it is
+    /// extended by any bytes this node consumes.
+    fn open(self, key: &mut Vec<u8>) -> NodeIter<Self>;
+}
+
+/// The children of a trie node, each with the byte that leads to it.
+struct ChildIter<'a, V: 'a> {
+    inner: btree_map::Range<'a, u8, Box<Node<V>>>,
+}
+
+impl<'a, V> Iterator for ChildIter<'a, V> {
+    type Item = (u8, &'a Node<V>);
+
+    fn next(&mut self) -> Option<(u8, &'a Node<V>)> {
+        self.inner.next().map(|(byte, child)| (*byte, child.as_ref()))
+    }
+}
+
+impl<'a, V> DoubleEndedIterator for ChildIter<'a, V> {
+    fn next_back(&mut self) -> Option<(u8, &'a Node<V>)> {
+        self.inner.next_back().map(|(byte, child)| (*byte, child.as_ref()))
+    }
+}
+
+/// The children of a trie node, each with the byte that leads to it, borrowed mutably.
+struct ChildIterMut<'a, V: 'a> {
+    inner: btree_map::IterMut<'a, u8, Box<Node<V>>>,
+}
+
+impl<'a, V> Iterator for ChildIterMut<'a, V> {
+    type Item = (u8, &'a mut Node<V>);
+
+    fn next(&mut self) -> Option<(u8, &'a mut Node<V>)> {
+        self.inner.next().map(|(byte, child)| (*byte, child.as_mut()))
+    }
+}
+
+impl<'a, V> DoubleEndedIterator for ChildIterMut<'a, V> {
+    fn next_back(&mut self) -> Option<(u8, &'a mut Node<V>)> {
+        self.inner.next_back().map(|(byte, child)| (*byte, child.as_mut()))
+    }
+}
+
+impl<'a, V> IterHandle for &'a Node<V> {
+    type Value = &'a V;
+    type Children = ChildIter<'a, V>;
+
+    fn open(self, key: &mut Vec<u8>) -> NodeIter<Self> {
+        match *self {
+            Node::Trie(ref node) => NodeIter::Trie(node.iter(key.len())),
+            Node::Prefix(ref node) => {
+                key.extend_from_slice(&node.key);
+                NodeIter::Prefix(node.iter(key.len()))
+            }
+        }
+    }
+}
+
+impl<'a, V> IterHandle for &'a mut Node<V> {
+    type Value = &'a mut V;
+    type Children = ChildIterMut<'a, V>;
+
+    fn open(self, key: &mut Vec<u8>) -> NodeIter<Self> {
+        match *self {
+            Node::Trie(ref mut node) => NodeIter::Trie(node.iter_mut(key.len())),
+            Node::Prefix(ref mut node) => {
+                key.extend_from_slice(&node.key);
+                NodeIter::Prefix(node.iter_mut(key.len()))
+            }
+        }
+    }
+}
+
+impl<V> IterHandle for Box<Node<V>> {
+    type Value = V;
+    type Children = btree_map::IntoIter<u8, Box<Node<V>>>;
+
+    fn open(self, key: &mut Vec<u8>) -> NodeIter<Self> {
+        match *self {
+            Node::Trie(node) => NodeIter::Trie(node.into_iter(key.len())),
+            Node::Prefix(node) => {
+                key.extend_from_slice(&node.key);
+                NodeIter::Prefix(node.into_iter(key.len()))
+            }
+        }
+    }
+}
+
+/// A shared reference to a node, which also allows the root of the map to be referenced.
+enum NodeRef<'a, V: 'a> {
+    Trie(&'a TrieNode<V>),
+    Prefix(&'a PrefixNode<V>),
+}
+
+impl<'a, V> NodeRef<'a, V> {
+    fn new(node: &'a Node<V>) -> Self {
+        match *node {
+            Node::Trie(ref node) => NodeRef::Trie(node),
+            Node::Prefix(ref node) => NodeRef::Prefix(node),
+        }
+    }
+
+    /// Follow `byte` down from `offset` bytes into this node's key, and return the node and
+    /// offset that it leads to.
+    fn step(self, offset: usize, byte: u8) -> Option<(NodeRef<'a, V>, usize)> {
+        match self {
+            NodeRef::Trie(node) => {
+                node.children.get(&byte).map(|child| (NodeRef::new(child), 0))
+            }
+            NodeRef::Prefix(node) => {
+                if offset < node.key.len() {
+                    if node.key[offset] == byte {
+                        Some((self, offset + 1))
+                    } else {
+                        None
+                    }
+                } else {
+                    match node.child {
+                        Some(ref child) => NodeRef::new(child).step(0, byte),
+                        None => None,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, V> Clone for NodeRef<'a, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, V> Copy for NodeRef<'a, V> {}
+
+impl<'a, V: Debug> NodeRef<'a, V> {
+    /// Write this node and its descendants as Graphviz statements, and return the id of the node.
+    fn write_dot(self, out: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+
+        let (shape, label, value) = match self {
+            NodeRef::Trie(node) => ("circle", String::new(), node.value.as_ref()),
+            NodeRef::Prefix(node) => {
+                ("box", format!("\"{}\"", escape(&node.key)), node.value.as_ref())
+            }
+        };
+
+        match value {
+            Some(value) => {
+                let label = if label.is_empty() {
+                    format!("{:?}", value)
+                } else {
+                    format!("{} = {:?}", label, value)
+                };
+
+                out.push_str(&format!("    node{} [shape={}, peripheries=2, label=\"{}\"];\n",
+                                      id,
+                                      shape,
+                                      escape_dot(&label)));
+            }
+            None => {
+                out.push_str(&format!("    node{} [shape={}, label=\"{}\"];\n",
+                                      id,
+                                      shape,
+                                      escape_dot(&label)));
+            }
+        }
+
+        match self {
+            NodeRef::Trie(node) => {
+                for (byte, child) in node.children.iter() {
+                    let child_id = NodeRef::new(child).write_dot(out, next_id);
+                    out.push_str(&format!("    node{} -> node{} [label=\"{}\"];\n",
+                                          id,
+                                          child_id,
+                                          escape_dot(&escape(&[*byte]))));
+                }
+            }
+            NodeRef::Prefix(node) => {
+                if let Some(ref child) = node.child {
+                    let child_id = NodeRef::new(child).write_dot(out, next_id);
+                    out.push_str(&format!("    node{} -> node{} [label=\"{}\"];\n",
+                                          id,
+                                          child_id,
+                                          escape_dot(&escape(&node.key))));
+                }
+            }
+        }
+
+        id
+    }
+
+    /// Write this node on its own line, reached through `byte` if its parent is a trie node, and
+    /// then its descendants. `indent` holds the tree lines of the ancestors.
+    fn fmt_tree(self,
+                f: &mut fmt::Formatter,
+                byte: Option<u8>,
+                indent: &mut String) -> fmt::Result {
+        if let Some(byte) = byte {
+            write!(f, "'{}' ", escape(&[byte]))?;
+        }
+
+        let value = match self {
+            NodeRef::Trie(node) => {
+                write!(f, "trie")?;
+                node.value.as_ref()
+            }
+            NodeRef::Prefix(node) => {
+                write!(f, "prefix \"{}\"", escape(&node.key))?;
+                node.value.as_ref()
+            }
+        };
+
+        if let Some(value) = value {
+            write!(f, " = {:?}", value)?;
+        }
+
+        writeln!(f)?;
+
+        match self {
+            NodeRef::Trie(node) => {
+                let mut children = node.children.iter().peekable();
+
+                while let Some((byte, child)) = children.next() {
+                    let last = children.peek().is_none();
+                    NodeRef::new(child).fmt_child(f, Some(*byte), indent, last)?;
+                }
+
+                Ok(())
+            }
+            NodeRef::Prefix(node) => {
+                match node.child {
+                    Some(ref child) => NodeRef::new(child).fmt_child(f, None, indent, true),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn fmt_child(self,
+                 f: &mut fmt::Formatter,
+                 byte: Option<u8>,
+                 indent: &mut String,
+                 last: bool) -> fmt::Result {
+        write!(f, "{}{}", indent, if last { "`-- " } else { "|-- " })?;
+
+        let len = indent.len();
+        indent.push_str(if last { "    " } else { "|   " });
+
+        let result = self.fmt_tree(f, byte, indent);
+
+        indent.truncate(len);
+        result
+    }
+}
+
+enum NodeIter<H: IterHandle> {
+    Trie(TrieIter<H>),
+    Prefix(PrefixIter<H>),
+}
+
+/// What a `RawIter` should do next with a node.
+enum Step<H: IterHandle> {
+    Value(H::Value),
+    /// Descend into a child, after pushing its byte onto the key if it is a trie node's child.
+    Child(Option<u8>, H),
+    Done,
+}
+
+impl<H: IterHandle> NodeIter<H> {
+    /// The length of the key of this node.
+    fn key_len(&self) -> usize {
+        match *self {
+            NodeIter::Trie(ref iter) => iter.key_len,
+            NodeIter::Prefix(ref iter) => iter.key_len,
+        }
+    }
+
+    /// Step forward through the node, which yields its value before its children.
+    fn next(&mut self) -> Step<H> {
+        match *self {
+            NodeIter::Trie(ref mut iter) => {
+                if let Some(value) = iter.value.take() {
+                    return Step::Value(value);
+                }
+
+                match iter.children.next() {
+                    Some((byte, child)) => Step::Child(Some(byte), child),
+                    None => Step::Done,
+                }
+            }
+            NodeIter::Prefix(ref mut iter) => {
+                if let Some(value) = iter.value.take() {
+                    return Step::Value(value);
+                }
+
+                match iter.child.take() {
+                    Some(child) => Step::Child(None, child),
+                    None => Step::Done,
+                }
+            }
+        }
+    }
+
+    /// Step backward through the node, which yields its children before its value.
+    fn next_back(&mut self) -> Step<H> {
+        match *self {
+            NodeIter::Trie(ref mut iter) => {
+                if let Some((byte, child)) = iter.children.next_back() {
+                    return Step::Child(Some(byte), child);
+                }
+
+                match iter.value.take() {
+                    Some(value) => Step::Value(value),
+                    None => Step::Done,
+                }
+            }
+            NodeIter::Prefix(ref mut iter) => {
+                if let Some(child) = iter.child.take() {
+                    return Step::Child(None, child);
+                }
+
+                match iter.value.take() {
+                    Some(value) => Step::Value(value),
+                    None => Step::Done,
+                }
+            }
+        }
+    }
+}
+
+impl<V> From<TrieNode<V>> for Node<V> {
+    fn from(node: TrieNode<V>) -> Self {
+        Node::Trie(node)
+    }
+}
+
+impl<V> From<PrefixNode<V>> for Node<V> {
+    fn from(node: PrefixNode<V>) -> Self {
+        Node::Prefix(node)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TrieNode<V> {
+    children: BTreeMap<u8, Box<Node<V>>>,
+    value: Option<V>,
+    /// The number of values held in this node and all of its descendants.
+    count: usize,
+}
+
+impl<V> TrieNode<V> {
+    fn empty() -> Self {
+        TrieNode::with_value(None)
+    }
+
+    fn with_value(value: Option<V>) -> Self {
+        TrieNode {
+            children: BTreeMap::new(),
+            count: if value.is_some() { 1 } else { 0 },
+            value: value,
+        }
+    }
+
+    fn insert_child(&mut self, byte: u8, node: Node<V>) {
+        self.count += node.count();
+        self.children.insert(byte, Box::new(node));
+    }
+
+    fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn insert<O: TrieObserver>(&mut self, key: &[u8], value: V, observer: &mut O) -> Option<V> {
+        let old_value = match key.first() {
+            Some(byte) => {
+                let key = &key[1..];
+
+                match self.children.entry(*byte) {
+                    btree_map::Entry::Occupied(mut entry) => {
+                        entry.get_mut().insert(key, value, observer)
+                    }
+                    btree_map::Entry::Vacant(entry) => {
+                        observer.node_created(NodeKind::Prefix);
+
+                        let node = PrefixNode::new(key.to_owned(), value);
+                        entry.insert(Box::new(Node::from(node)));
+                        None
+                    }
+                }
+            }
+            None => {
+                let mut old_value = None;
+                mem::swap(&mut self.value, &mut old_value);
+
+                self.value = Some(value);
+
+                old_value
+            }
+        };
+
+        if old_value.is_none() {
+            self.count += 1;
+        }
+
+        old_value
+    }
+
+    fn get<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
+        match key.first() {
+            Some(byte) => {
+                match self.children.get(byte) {
+                    Some(child) => child.get(&key[1..]),
+                    None => None,
+                }
+            }
+            None => {
+                self.value.as_ref()
+            }
+        }
+    }
+
+    fn get_mut<'a>(&'a mut self, key: &[u8]) -> Option<&'a mut V> {
+        match key.first() {
+            Some(byte) => {
+                match self.children.get_mut(byte) {
+                    Some(child) => child.get_mut(&key[1..]),
+                    None => None,
+                }
+            }
+            None => {
+                self.value.as_mut()
+            }
+        }
+    }
+
+    fn entry_step(&self, key: &[u8]) -> EntryStep {
+        match key.first() {
+            Some(byte) => {
+                if self.children.contains_key(byte) {
+                    EntryStep::Descend(1)
+                } else {
+                    EntryStep::Vacant
+                }
+            }
+            None => {
+                if self.value.is_some() {
+                    EntryStep::Occupied
+                } else {
+                    EntryStep::Vacant
+                }
+            }
+        }
+    }
+
+    fn find_entry<'a>(&'a mut self,
+                      key: &[u8],
+                      pos: usize,
+                      counts: &mut Vec<&'a mut usize>) -> EntryNode<'a, V> {
+        match self.entry_step(key) {
+            EntryStep::Occupied => EntryNode::Occupied(self.value.as_mut().unwrap()),
+            EntryStep::Vacant => EntryNode::Vacant(VacantNode::Trie(self), pos),
+            EntryStep::Descend(n) => {
+                counts.push(&mut self.count);
+                self.children.get_mut(&key[0]).unwrap().find_entry(&key[n..], pos + n, counts)
+            }
+        }
+    }
+
+    fn rank(&self, key: &[u8]) -> usize {
+        match key.first() {
+            Some(byte) => {
+                // Our value and every child before the key's next byte sort before the key.
+                let mut rank = if self.value.is_some() { 1 } else { 0 };
+
+                for (_, child) in self.children.range(..*byte) {
+                    rank += child.count();
+                }
+
+                match self.children.get(byte) {
+                    Some(child) => rank + child.rank(&key[1..]),
+                    None => rank,
+                }
+            }
+            None => 0,
+        }
+    }
+
+    fn nth<'a>(&'a self, mut n: usize, key: &mut Vec<u8>) -> &'a V {
+        if let Some(ref value) = self.value {
+            if n == 0 {
+                return value;
+            }
+            n -= 1;
+        }
+
+        for (byte, child) in self.children.iter() {
+            let count = child.count();
+
+            if n < count {
+                key.push(*byte);
+                return child.nth(n, key);
+            }
+
+            n -= count;
+        }
+
+        unreachable!("index past the count of the node");
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> usize {
+        match prefix.first() {
+            Some(byte) => {
+                match self.children.get(byte) {
+                    Some(child) => child.count_prefix(&prefix[1..]),
+                    None => 0,
+                }
+            }
+            None => self.count,
+        }
+    }
+
+    fn find_prefix<'a>(&'a self, prefix: &[u8], key: &mut Vec<u8>) -> Option<NodeIter<&'a Node<V>>> {
+        match prefix.first() {
+            Some(byte) => {
+                match self.children.get(byte) {
+                    Some(child) => {
+                        key.push(*byte);
+                        child.find_prefix(&prefix[1..], key)
+                    }
+                    None => None,
+                }
+            }
+            None => {
+                Some(NodeIter::Trie(self.iter(key.len())))
+            }
+        }
+    }
+
+    fn seek<'a>(&'a self,
+                start: Bound<&[u8]>,
+                end: Bound<&[u8]>,
+                side: Side,
+                iter: &mut RawIter<&'a Node<V>>) {
+        // If a bound continues past this node, then our value and the children before (or after)
+        // the bound's next byte are outside of the range, and the child at that byte needs to be
+        // sought into.
+        let (start_ok, lower, start_byte) = match start {
+            Bound::Included(bound) | Bound::Excluded(bound) => {
+                match bound.first() {
+                    Some(&byte) => (false, Bound::Excluded(byte), Some(byte)),
+                    None => (is_included(start), Bound::Unbounded, None),
+                }
+            }
+            Bound::Unbounded => (true, Bound::Unbounded, None),
+        };
+
+        let (end_ok, upper, end_byte) = match end {
+            Bound::Included(bound) | Bound::Excluded(bound) => {
+                match bound.first() {
+                    Some(&byte) => (true, Bound::Excluded(byte), Some(byte)),
+                    // Every child is past the end of the range.
+                    None => (is_included(end), Bound::Excluded(0), None),
+                }
+            }
+            Bound::Unbounded => (true, Bound::Unbounded, None),
+        };
+
+        let value = if start_ok && end_ok { self.value.as_ref() } else { None };
+        let key_len = iter.key_len(side);
+        iter.push(side, NodeIter::Trie(self.iter_bounded(key_len, value, (lower, upper))));
+
+        match (start_byte, end_byte) {
+            (Some(start_byte), Some(end_byte)) if start_byte == end_byte => {
+                if let Some(child) = self.children.get(&start_byte) {
+                    iter.extend_key(side, &[start_byte]);
+                    child.seek(bound_tail(start, 1), bound_tail(end, 1), side, iter);
+                }
+            }
+            (start_byte, end_byte) => {
+                if let Some(byte) = start_byte {
+                    if let Some(child) = self.children.get(&byte) {
+                        iter.extend_key(Side::Front, &[byte]);
+                        child.seek(bound_tail(start, 1), Bound::Unbounded, Side::Front, iter);
+                    }
+                }
+
+                if let Some(byte) = end_byte {
+                    if let Some(child) = self.children.get(&byte) {
+                        iter.extend_key(Side::Back, &[byte]);
+                        child.seek(Bound::Unbounded, bound_tail(end, 1), Side::Back, iter);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove<O: TrieObserver>(&mut self, key: &[u8], observer: &mut O) -> Option<V> {
+        let value = match key.first() {
+            Some(byte) => {
+                let (value, vacant) = match self.children.get_mut(byte) {
+                    Some(child) => {
+                        let value = child.remove(&key[1..], observer);
+                        (value, child.is_vacant())
+                    }
+                    None => { return None; }
+                };
+
+                if vacant {
+                    self.children.remove(byte);
+                }
+
+                value
+            }
+            None => {
+                self.value.take()
+            }
+        };
+
+        if value.is_some() {
+            self.count -= 1;
+        }
+
+        value
+    }
+
+    fn split_off<O: TrieObserver>(&mut self, key: &[u8], observer: &mut O) -> Option<TrieNode<V>> {
+        let byte = match key.first() {
+            Some(&byte) => byte,
+            None => {
+                // Every key under this node is at least as large as the empty key.
+                if self.count == 0 {
+                    return None;
+                }
+
+                return Some(mem::replace(self, TrieNode::empty()));
+            }
+        };
+
+        // The children after the key's next byte move over whole.
+        let mut children = match byte.checked_add(1) {
+            Some(next) => self.children.split_off(&next),
+            None => BTreeMap::new(),
+        };
+
+        let (right, vacant) = match self.children.get_mut(&byte) {
+            Some(child) => (child.split_off(&key[1..], observer), child.is_vacant()),
+            None => (None, false),
+        };
+
+        if vacant {
+            self.children.remove(&byte);
+        }
+
+        if let Some(right) = right {
+            children.insert(byte, Box::new(right));
+        }
+
+        if children.is_empty() {
+            return None;
+        }
+
+        let mut trie = TrieNode::empty();
+        for (byte, child) in children {
+            trie.insert_child(byte, *child);
+        }
+
+        self.count -= trie.count;
+
+        Some(trie)
+    }
+
+    fn split_off_prefix<O: TrieObserver>(&mut self,
+                                         prefix: &[u8],
+                                         path: &mut Vec<u8>,
+                                         observer: &mut O) -> Option<Node<V>> {
+        let byte = prefix[0];
+        let prefix = &prefix[1..];
+
+        let covers = match self.children.get(&byte) {
+            Some(child) => child.covers(prefix),
+            None => { return None; }
+        };
+
+        path.push(byte);
+
+        let node = if covers {
+            // The child holds exactly the keys under the prefix, so it is detached whole.
+            self.children.remove(&byte).map(|child| *child)
+        } else {
+            let (node, vacant) = {
+                let child = self.children.get_mut(&byte).unwrap();
+                let node = child.split_off_prefix(prefix, path, observer);
+                (node, child.is_vacant())
+            };
+
+            if vacant {
+                self.children.remove(&byte);
+            }
+
+            node
+        };
+
+        if let Some(ref node) = node {
+            self.count -= node.count();
+        }
+
+        node
+    }
+
+    fn retain<F, O>(&mut self, key: &mut Vec<u8>, f: &mut F, observer: &mut O)
+        where F: FnMut(&[u8], &mut V) -> bool,
+              O: TrieObserver,
+    {
+        let keep = match self.value {
+            Some(ref mut value) => f(key, value),
+            None => true,
+        };
+
+        if !keep {
+            self.value = None;
+        }
+
+        let mut vacant = Vec::new();
+
+        for (byte, child) in self.children.iter_mut() {
+            key.push(*byte);
+            child.retain(key, f, observer);
+            key.pop();
+
+            if child.is_vacant() {
+                vacant.push(*byte);
+            }
+        }
+
+        for byte in vacant {
+            self.children.remove(&byte);
+        }
+
+        self.recount();
+    }
+
+    /// Ask `f` about each of our children. Our own value is kept.
+    fn retain_prefixes<F, O>(&mut self, key: &mut Vec<u8>, f: &mut F, observer: &mut O)
+        where F: FnMut(&[u8]) -> Retain,
+              O: TrieObserver,
+    {
+        let mut vacant = Vec::new();
+
+        for (byte, child) in self.children.iter_mut() {
+            key.push(*byte);
+            child.retain_prefixes(key, f, observer);
+            key.pop();
+
+            if child.is_vacant() {
+                vacant.push(*byte);
+            }
+        }
+
+        for byte in vacant {
+            self.children.remove(&byte);
+        }
+
+        self.recount();
+    }
+
+    /// Recompute our count from our value and children.
+    fn recount(&mut self) {
+        let mut count = if self.value.is_some() { 1 } else { 0 };
+
+        for child in self.children.values() {
+            count += child.count();
+        }
+
+        self.count = count;
+    }
+
+    fn validate(&self, key: &mut Vec<u8>, is_root: bool) -> Result<usize, InvariantError> {
+        if !is_root && self.children.len() < 2 {
+            return Err(InvariantError::SparseTrie(key.clone()));
+        }
+
+        let mut count = if self.value.is_some() { 1 } else { 0 };
+
+        for (byte, child) in self.children.iter() {
+            key.push(*byte);
+            count += child.validate(key)?;
+            key.pop();
+        }
+
+        if count != self.count {
+            return Err(InvariantError::Count(key.clone()));
+        }
+
+        Ok(count)
+    }
+
+    fn merge<F, O>(mut self,
+                   other: TrieNode<V>,
+                   key: &mut Vec<u8>,
+                   f: &mut F,
+                   observer: &mut O) -> TrieNode<V>
+        where F: FnMut(&[u8], V, V) -> V,
+              O: TrieObserver,
+    {
+        let value = match (self.value.take(), other.value) {
+            (Some(lhs), Some(rhs)) => {
+                observer.value_replaced(key);
+                Some(f(key, lhs, rhs))
+            }
+            (lhs, rhs) => lhs.or(rhs),
+        };
+
+        let mut trie = TrieNode::with_value(value);
+        let mut others = other.children;
+
+        for (byte, child) in self.children {
+            let child = match others.remove(&byte) {
+                Some(other) => {
+                    key.push(byte);
+                    let child = child.merge(*other, key, f, observer);
+                    key.pop();
+                    child
+                }
+                None => *child,
+            };
+
+            trie.insert_child(byte, child);
+        }
+
+        // Whatever is left only exists in the other trie.
+        for (byte, child) in others {
+            trie.insert_child(byte, *child);
+        }
+
+        trie
+    }
+
+    /// Convert a trie node with at most one child into the equivalent prefix node.
+    fn into_prefix<O: TrieObserver>(mut self, observer: &mut O) -> PrefixNode<V> {
+        assert!(self.children.len() < 2);
+
+        let child = match self.children.keys().next().cloned() {
+            Some(byte) => {
+                let child = *self.children.remove(&byte).unwrap();
+
+                // A prefix child takes the byte onto its key, but a trie child needs a new node.
+                if let Node::Trie(_) = child {
+                    observer.node_created(NodeKind::Prefix);
+                }
+
+                Some(PrefixNode::from_edge(byte, child))
+            }
+            None => None,
+        };
+
+        match child {
+            Some(child) => {
+                if self.value.is_some() {
+                    observer.node_created(NodeKind::Prefix);
+                    PrefixNode::with_child(Vec::new(), self.value, Some(Box::new(Node::from(child))))
+                } else {
+                    child
+                }
+            }
+            None => {
+                observer.node_created(NodeKind::Prefix);
+                PrefixNode::with_child(Vec::new(), self.value, None)
+            }
+        }
+    }
+}
+
+impl<V> TrieNode<V> {
+    fn iter(&self, key_len: usize) -> TrieIter<&Node<V>> {
+        self.iter_bounded(key_len, self.value.as_ref(), (Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// Iterate over `value`, and then the children whose bytes fall within `range`.
+    fn iter_bounded<'a>(&'a self,
+                        key_len: usize,
+                        value: Option<&'a V>,
+                        range: (Bound<u8>, Bound<u8>)) -> TrieIter<&'a Node<V>> {
+        // `BTreeMap::range` panics if the range is inverted.
+        let empty = match range {
+            (Bound::Included(lower), Bound::Included(upper)) => lower > upper,
+            (Bound::Included(lower), Bound::Excluded(upper)) |
+            (Bound::Excluded(lower), Bound::Included(upper)) |
+            (Bound::Excluded(lower), Bound::Excluded(upper)) => lower >= upper,
+            _ => false,
+        };
+
+        let range = if empty { (Bound::Included(0), Bound::Excluded(0)) } else { range };
+
+        TrieIter {
+            key_len: key_len,
+            value: value,
+            children: ChildIter { inner: self.children.range(range) },
+        }
+    }
+
+    fn iter_mut(&mut self, key_len: usize) -> TrieIter<&mut Node<V>> {
+        TrieIter {
+            key_len: key_len,
+            value: self.value.as_mut(),
+            children: ChildIterMut { inner: self.children.iter_mut() },
+        }
+    }
+
+    fn into_iter(self, key_len: usize) -> TrieIter<Box<Node<V>>> {
+        TrieIter {
+            key_len: key_len,
+            value: self.value,
+            children: self.children.into_iter(),
+        }
+    }
+}
+
+struct TrieIter<H: IterHandle> {
+    key_len: usize,
+    value: Option<H::Value>,
+    children: H::Children,
+}
+
+#[derive(Clone, Debug)]
+struct PrefixNode<V> {
+    key: Vec<u8>,
+    value: Option<V>,
+    child: Option<Box<Node<V>>>,
+    /// The number of values held in this node and all of its descendants.
+    count: usize,
+}
+
+impl<V> PrefixNode<V> {
+    fn new(key: Vec<u8>, value: V) -> Self {
+        PrefixNode::with_child(key, Some(value), None)
+    }
+
+    fn with_child(key: Vec<u8>, value: Option<V>, child: Option<Box<Node<V>>>) -> Self {
+        let count = match child {
+            Some(ref child) => child.count(),
+            None => 0,
+        };
+
+        PrefixNode {
+            key: key,
+            count: if value.is_some() { count + 1 } else { count },
+            value: value,
+            child: child,
+        }
+    }
+
+    fn find_difference(&self, key: &[u8]) -> Option<usize> {
+        key.iter()
+            .zip(self.key.iter())
+            .position(|(lhs, rhs)| lhs != rhs)
+    }
+
+    fn insert<O: TrieObserver>(&mut self,
+                               key: &[u8],
+                               value: V,
+                               observer: &mut O) -> InsertResult<V> {
+        match self.find_difference(key) {
+            Some(pos) => {
+                if pos == 0 {
+                    observer.burst_into_trie();
+                } else {
+                    observer.prefix_split(self.key.len(), pos);
+                }
+
+                let trie = self.burst_and_insert(pos, key, value, observer);
+
+                // If we bursted on the first byte, then transform this node into a trie.
+                if pos == 0 {
+                    InsertResult::Burst(trie)
+                } else {
+                    self.count = trie.count;
+                    self.child = Some(Box::new(Node::from(trie)));
+                    InsertResult::Ok(None)
+                }
+            }
+            None => {
+                // If we have an exact collision, then just update the value. Otherwise
+                // insert the remaining key into our child.
+                if key.len() == self.key.len() {
+                    let mut old_value = None;
+                    mem::swap(&mut self.value, &mut old_value);
+
+                    self.value = Some(value);
+
+                    if old_value.is_none() {
+                        self.count += 1;
+                    }
+
+                    InsertResult::Ok(old_value)
+                } else if key.len() < self.key.len() {
+                    // The key is a prefix of our key, so split off our tail into a new child.
+                    observer.prefix_split(self.key.len(), key.len());
+                    observer.node_created(NodeKind::Prefix);
+
+                    let suffix = self.key.split_off(key.len());
+
+                    let mut self_value = Some(value);
+                    mem::swap(&mut self_value, &mut self.value);
+
+                    let mut self_child = None;
+                    mem::swap(&mut self_child, &mut self.child);
+
+                    let child = PrefixNode::with_child(suffix, self_value, self_child);
+                    self.child = Some(Box::new(Node::from(child)));
+                    self.count += 1;
+
+                    InsertResult::Ok(None)
+                } else {
+                    let key = &key[self.key.len()..];
+
+                    if let Some(ref mut child) = self.child {
+                        let old_value = child.insert(key, value, observer);
+
+                        if old_value.is_none() {
+                            self.count += 1;
+                        }
+
+                        // If our child burst into a trie, and we do not have a key of our own,
+                        // then we should become that trie.
+                        let burst = match **child {
+                            Node::Trie(_) => self.key.is_empty(),
+                            Node::Prefix(_) => false,
+                        };
+
+                        if burst {
+                            return InsertResult::Burst(self.burst_into_trie(observer));
+                        }
+
+                        InsertResult::Ok(old_value)
+                    } else {
+                        observer.node_created(NodeKind::Prefix);
+
+                        let child = PrefixNode::new(key.to_owned(), value);
+                        self.child = Some(Box::new(Node::from(child)));
+                        self.count += 1;
+
+                        InsertResult::Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    fn get<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
+        match self.find_difference(key) {
+            Some(_) => None,
+            None => {
+                if key.len() == self.key.len() {
+                    self.value.as_ref()
+                } else if key.len() < self.key.len() {
+                    None
+                } else {
+                    match self.child {
+                        Some(ref child) => child.get(&key[self.key.len()..]),
+                        None => None,
+                    }
+                }
+            }
+        }
+    }
+
+    fn get_mut<'a>(&'a mut self, key: &[u8]) -> Option<&'a mut V> {
+        match self.find_difference(key) {
+            Some(_) => None,
+            None => {
+                if key.len() == self.key.len() {
+                    self.value.as_mut()
+                } else if key.len() < self.key.len() {
+                    None
+                } else {
+                    match self.child {
+                        Some(ref mut child) => child.get_mut(&key[self.key.len()..]),
+                        None => None,
+                    }
+                }
+            }
+        }
+    }
+
+    fn entry_step(&self, key: &[u8]) -> EntryStep {
+        if key.len() < self.key.len() || self.find_difference(key).is_some() {
+            return EntryStep::Vacant;
+        }
+
+        if key.len() == self.key.len() {
+            if self.value.is_some() {
+                return EntryStep::Occupied;
+            } else {
+                return EntryStep::Vacant;
+            }
+        }
+
+        match self.child {
+            Some(ref child) => {
+                // If our key is empty and our child would burst on its first byte, then we need
+                // to burst into a trie ourselves, so the key must be inserted from here.
+                if let Node::Prefix(ref child) = **child {
+                    if self.key.is_empty() && child.key.first() != key.first() {
+                        return EntryStep::Vacant;
+                    }
+                }
+
+                EntryStep::Descend(self.key.len())
+            }
+            None => EntryStep::Vacant,
+        }
+    }
+
+    fn rank(&self, key: &[u8]) -> usize {
+        match self.relation(key) {
+            Relation::Below => self.count,
+            Relation::Above | Relation::Equal => 0,
+            Relation::Within => {
+                let rank = if self.value.is_some() { 1 } else { 0 };
+
+                match self.child {
+                    Some(ref child) => rank + child.rank(&key[self.key.len()..]),
+                    None => rank,
+                }
+            }
+        }
+    }
+
+    fn nth<'a>(&'a self, mut n: usize, key: &mut Vec<u8>) -> &'a V {
+        key.extend_from_slice(&self.key);
+
+        if let Some(ref value) = self.value {
+            if n == 0 {
+                return value;
+            }
+            n -= 1;
+        }
+
+        self.child.as_ref().unwrap().nth(n, key)
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> usize {
+        if prefix.len() <= self.key.len() {
+            if self.key.starts_with(prefix) { self.count } else { 0 }
+        } else if prefix.starts_with(&self.key) {
+            match self.child {
+                Some(ref child) => child.count_prefix(&prefix[self.key.len()..]),
+                None => 0,
+            }
+        } else {
+            0
+        }
+    }
+
+    fn find_prefix<'a>(&'a self, prefix: &[u8], key: &mut Vec<u8>) -> Option<NodeIter<&'a Node<V>>> {
+        // The prefix may end in the middle of our key, in which case every key under this node
+        // starts with the prefix.
+        if prefix.len() <= self.key.len() {
+            if self.key.starts_with(prefix) {
+                key.extend_from_slice(&self.key);
+                Some(NodeIter::Prefix(self.iter(key.len())))
+            } else {
+                None
+            }
+        } else if prefix.starts_with(&self.key) {
+            match self.child {
+                Some(ref child) => {
+                    key.extend_from_slice(&self.key);
+                    child.find_prefix(&prefix[self.key.len()..], key)
+                }
+                None => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Compare every key under this node against `bound`.
+    fn relation(&self, bound: &[u8]) -> Relation {
+        match self.find_difference(bound) {
+            Some(pos) => {
+                if self.key[pos] < bound[pos] {
+                    Relation::Below
+                } else {
+                    Relation::Above
+                }
+            }
+            None => {
+                if bound.len() < self.key.len() {
+                    Relation::Above
+                } else if bound.len() == self.key.len() {
+                    Relation::Equal
+                } else {
+                    Relation::Within
+                }
+            }
         }
     }
 
-    fn len(&self) -> usize {
-        self.children.len()
-    }
+    fn seek<'a>(&'a self,
+                start: Bound<&[u8]>,
+                end: Bound<&[u8]>,
+                side: Side,
+                iter: &mut RawIter<&'a Node<V>>) {
+        let start_relation = match start {
+            Bound::Included(bound) | Bound::Excluded(bound) => self.relation(bound),
+            Bound::Unbounded => Relation::Above,
+        };
 
-    fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
+        let end_relation = match end {
+            Bound::Included(bound) | Bound::Excluded(bound) => self.relation(bound),
+            Bound::Unbounded => Relation::Below,
+        };
 
-    fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
-        match key.first() {
-            Some(byte) => {
-                println!("TrieNode.insert: {:?}", byte);
+        // Work out if our value is in range, and whether our child is entirely in range, entirely
+        // past the end, or needs to be sought into.
+        let (start_ok, seek_start) = match start_relation {
+            Relation::Below => { return; }
+            Relation::Above => (true, false),
+            Relation::Equal => (is_included(start), false),
+            Relation::Within => (false, true),
+        };
 
-                let key = &key[1..];
+        let (end_ok, seek_end, past_end) = match end_relation {
+            Relation::Below => (true, false, false),
+            Relation::Above => { return; }
+            Relation::Equal => (is_included(end), false, true),
+            Relation::Within => (true, true, false),
+        };
 
-                match self.children.entry(*byte) {
-                    btree_map::Entry::Occupied(mut entry) => {
-                        println!("TrieNode.insert: update: {:?}", key);
+        iter.extend_key(side, &self.key);
 
-                        entry.get_mut().insert(key, value)
-                    }
-                    btree_map::Entry::Vacant(entry) => {
-                        println!("TrieNode.insert: prefix: {:?}", key);
+        let child = if seek_start || seek_end || past_end {
+            None
+        } else {
+            self.child.as_deref()
+        };
 
-                        let node = PrefixNode::new(key.to_owned(), value);
-                        entry.insert(Box::new(Node::from(node)));
-                        None
-                    }
-                }
-            }
-            None => {
-                println!("TrieNode.insert: empty");
+        let key_len = iter.key_len(side);
+        iter.push(side, NodeIter::Prefix(PrefixIter {
+            key_len: key_len,
+            value: if start_ok && end_ok { self.value.as_ref() } else { None },
+            child: child,
+        }));
 
-                let mut old_value = None;
-                mem::swap(&mut self.value, &mut old_value);
+        if past_end {
+            return;
+        }
 
-                self.value = Some(value);
+        if let Some(ref child) = self.child {
+            let len = self.key.len();
 
-                old_value
+            match (seek_start, seek_end) {
+                (true, true) => {
+                    child.seek(bound_tail(start, len), bound_tail(end, len), side, iter);
+                }
+                (true, false) => {
+                    child.seek(bound_tail(start, len), Bound::Unbounded, Side::Front, iter);
+                }
+                (false, true) => {
+                    child.seek(Bound::Unbounded, bound_tail(end, len), Side::Back, iter);
+                }
+                (false, false) => { }
             }
         }
     }
 
-    fn get<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
-        println!("TrieNode.get: {:?}", key);
+    fn remove<O: TrieObserver>(&mut self, key: &[u8], observer: &mut O) -> Option<V> {
+        if key.len() < self.key.len() || self.find_difference(key).is_some() {
+            return None;
+        }
 
-        match key.first() {
-            Some(byte) => {
-                match self.children.get(byte) {
-                    Some(child) => child.get(&key[1..]),
-                    None => None,
+        let value = if key.len() == self.key.len() {
+            self.value.take()
+        } else {
+            let (value, vacant) = match self.child {
+                Some(ref mut child) => {
+                    let value = child.remove(&key[self.key.len()..], observer);
+                    (value, child.is_vacant())
                 }
+                None => { return None; }
+            };
+
+            if vacant {
+                self.child = None;
             }
-            None => {
-                self.value.as_ref()
-            }
+
+            value
+        };
+
+        if value.is_some() {
+            self.count -= 1;
         }
+
+        value
     }
-}
 
-/*
-struct TrieIter<'a, V> {
-    key: &'a mut Vec<u8>,
-    iter: btree_map::Iter<'a, V>,
-    value: Option<&'a V>,
-    node_iter: Option<Iter<'a, V>>,
-}
+    fn split_off<O>(&mut self, key: &[u8], observer: &mut O) -> Option<PrefixNode<V>>
+        where O: TrieObserver,
+    {
+        match self.relation(key) {
+            Relation::Below => None,
+            Relation::Above | Relation::Equal => {
+                Some(mem::replace(self, PrefixNode::with_child(Vec::new(), None, None)))
+            }
+            Relation::Within => {
+                // Our value sorts before the key, so only part of our child moves.
+                let (right, vacant) = match self.child {
+                    Some(ref mut child) => {
+                        let right = child.split_off(&key[self.key.len()..], observer);
+                        (right, child.is_vacant())
+                    }
+                    None => { return None; }
+                };
 
-impl<'a, V> TrieIter<'a, V> {
-    fn new(key: &'a mut Vec<u8>,
-           value: Option<&'a V>,
-           iter: btree_map::Iter<'a, V>) -> Self {
-        TrieIter {
-            key: key,
-            value: value,
-            iter: iter,
-            node_iter: None,
+                if vacant {
+                    self.child = None;
+                }
+
+                right.map(|right| {
+                    self.count -= right.count();
+                    PrefixNode::with_child(self.key.clone(), None, Some(Box::new(right)))
+                })
+            }
         }
     }
-}
 
-impl<'a, V> Iterator for TrieIter<'a, V> {
-    type Item = (Vec<u8>, &'a V);
+    fn retain<F, O>(&mut self, key: &mut Vec<u8>, f: &mut F, observer: &mut O)
+        where F: FnMut(&[u8], &mut V) -> bool,
+              O: TrieObserver,
+    {
+        let len = key.len();
+        key.extend_from_slice(&self.key);
 
-    fn next(&mut self) -> Option<Item::Self> {
-        // First, optionally et=
-        match self.value.take() {
-            Some(value) => {
+        let keep = match self.value {
+            Some(ref mut value) => f(key, value),
+            None => true,
+        };
+
+        if !keep {
+            self.value = None;
+        }
+
+        let vacant = match self.child {
+            Some(ref mut child) => {
+                child.retain(key, f, observer);
+                child.is_vacant()
             }
-            None => { }
+            None => false,
+        };
+
+        if vacant {
+            self.child = None;
         }
 
-        loop {
+        key.truncate(len);
+        self.recount();
+    }
 
-            match self.node_iter {
-                Some(ref mut node_iter) => {
-                    match node_iter.next() {
-                        Some(item) => { return Some(item); }
-                        None => {
-                            // Reclaim the key back from the node iterator and remove the byte we
-                            // pushed onto the key.
-                            mem::swap(self.key, node_iter.key);
-                            key.pop();
+    fn retain_prefixes<F, O>(&mut self, key: &mut Vec<u8>, f: &mut F, observer: &mut O)
+        where F: FnMut(&[u8]) -> Retain,
+              O: TrieObserver,
+    {
+        let len = key.len();
+        key.extend_from_slice(&self.key);
+
+        match f(key) {
+            Retain::Keep => { }
+            Retain::Drop => {
+                self.value = None;
+                self.child = None;
+            }
+            Retain::Descend => {
+                let vacant = match self.child {
+                    Some(ref mut child) => {
+                        // A trie child shares our prefix, so it is not asked about it again.
+                        if let Node::Trie(ref mut trie) = **child {
+                            trie.retain_prefixes(key, f, observer);
+                        } else {
+                            child.retain_prefixes(key, f, observer);
                         }
+
+                        child.compact(observer);
+                        child.is_vacant()
                     }
+                    None => false,
+                };
+
+                if vacant {
+                    self.child = None;
                 }
-                None => { }
             }
+        }
+
+        key.truncate(len);
+        self.recount();
+    }
+
+    /// Recompute our count from our value and child.
+    fn recount(&mut self) {
+        let count = match self.child {
+            Some(ref child) => child.count(),
+            None => 0,
+        };
+
+        self.count = if self.value.is_some() { count + 1 } else { count };
+    }
 
-            match self.iter.next() {
-                Some((byte, node)) => {
-                    self.key.push(byte);
-                    self.node_iter = Some(node.iter());
+    fn validate(&self, key: &mut Vec<u8>) -> Result<usize, InvariantError> {
+        let error = match self.child {
+            None if self.value.is_none() => Some(InvariantError::EmptyLeaf(key.clone())),
+            Some(ref child) => {
+                match **child {
+                    Node::Trie(_) if self.key.is_empty() => {
+                        Some(InvariantError::EmptyPrefix(key.clone()))
+                    }
+                    Node::Trie(ref child) if child.value.is_some() => {
+                        Some(InvariantError::TrieChildValue(key.clone()))
+                    }
+                    Node::Prefix(_) if self.value.is_none() => {
+                        Some(InvariantError::UnmergedPrefix(key.clone()))
+                    }
+                    Node::Prefix(ref child) if child.key.is_empty() => {
+                        Some(InvariantError::EmptyPrefixChild(key.clone()))
+                    }
+                    _ => None,
                 }
-                None => { return None; }
             }
+            None => None,
+        };
+
+        if let Some(error) = error {
+            return Err(error);
         }
-    }
-}
-*/
 
-#[derive(Clone, Debug)]
-struct PrefixNode<V> {
-    key: Vec<u8>,
-    value: Option<V>,
-    child: Option<Box<Node<V>>>,
-}
+        let mut count = if self.value.is_some() { 1 } else { 0 };
+
+        if let Some(ref child) = self.child {
+            let len = key.len();
+            key.extend_from_slice(&self.key);
+            count += child.validate(key)?;
+            key.truncate(len);
+        }
 
+        if count != self.count {
+            return Err(InvariantError::Count(key.clone()));
+        }
 
-impl<V: Debug> PrefixNode<V> {
-    fn new(key: Vec<u8>, value: V) -> Self {
-        PrefixNode::with_child(key, value, None)
+        Ok(count)
     }
 
-    fn with_child(key: Vec<u8>, value: V, child: Option<Box<Node<V>>>) -> Self {
-        PrefixNode {
-            key: key,
-            value: Some(value),
-            child: child,
+    fn split_off_prefix<O: TrieObserver>(&mut self,
+                                         prefix: &[u8],
+                                         path: &mut Vec<u8>,
+                                         observer: &mut O) -> Option<Node<V>> {
+        // If the prefix ended within our key, our parent would have detached us, so it has to
+        // continue past our key.
+        if !prefix.starts_with(&self.key) {
+            return None;
         }
-    }
 
-    fn len(&self) -> usize {
-        if self.child.is_some() { 1 } else { 0 }
-    }
+        let prefix = &prefix[self.key.len()..];
 
-    fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
+        let covers = match self.child {
+            Some(ref child) => child.covers(prefix),
+            None => { return None; }
+        };
 
-    fn find_difference(&self, key: &[u8]) -> Option<usize> {
-        key.iter()
-            .zip(self.key.iter())
-            .position(|(lhs, rhs)| lhs != rhs)
-    }
+        path.extend_from_slice(&self.key);
+
+        let node = if covers {
+            self.child.take().map(|child| *child)
+        } else {
+            let (node, vacant) = {
+                let child = self.child.as_mut().unwrap();
+                let node = child.split_off_prefix(prefix, path, observer);
+                (node, child.is_vacant())
+            };
+
+            if vacant {
+                self.child = None;
+            }
 
-    fn insert(&mut self, key: &[u8], value: V) -> InsertResult<V> {
-        println!("PrefixNode.insert: self.key: {:?} other.key: {:?}", self.key, key);
+            node
+        };
 
-        match self.find_difference(key) {
-            Some(pos) => {
-                let trie = self.burst_and_insert(pos, key, value);
+        if let Some(ref node) = node {
+            self.count -= node.count();
+        }
 
-                // If we bursted on the first byte, then transform this node into a trie.
-                if pos == 0 {
-                    InsertResult::Burst(trie)
-                } else {
-                    self.child = Some(Box::new(Node::from(trie)));
-                    InsertResult::Ok(None)
+        node
+    }
+
+    /// Build the prefix node for the edge `byte` leading into `node`.
+    fn from_edge(byte: u8, node: Node<V>) -> Self {
+        match node {
+            Node::Prefix(mut node) => {
+                node.key.insert(0, byte);
+                node
+            }
+            Node::Trie(mut node) => {
+                let value = node.value.take();
+                if value.is_some() {
+                    node.count -= 1;
                 }
+                PrefixNode::with_child(vec![byte], value, Some(Box::new(Node::from(node))))
             }
-            None => {
-                // If we have an exact collision, then just update the value. Otherwise
-                // insert the remaining key into our child.
-                if key.len() == self.key.len() {
-                    println!("PrefixNode.insert: updating key");
+        }
+    }
 
-                    let mut old_value = None;
-                    mem::swap(&mut self.value, &mut old_value);
+    /// Build the prefix node for the bytes of `path` leading into `node`. `path` must not be empty.
+    fn from_path(path: &[u8], node: Node<V>) -> Self {
+        let (&byte, path) = path.split_last().unwrap();
 
-                    self.value = Some(value);
+        let mut node = PrefixNode::from_edge(byte, node);
 
-                    InsertResult::Ok(old_value)
-                } else {
-                    println!("PrefixNode.insert2: self.key: {:?} other.key: {:?}", self.key, key);
-                    let key = &key[self.key.len()..];
-                    println!("PrefixNode.insert3: splitting other.key: {:?}", key);
+        let mut key = path.to_owned();
+        key.extend_from_slice(&node.key);
+        node.key = key;
 
-                    if let Some(ref mut child) = self.child {
-                        InsertResult::Ok(child.insert(key, value))
-                    } else {
-                        let child = PrefixNode::new(key.to_owned(), value);
-                        self.child = Some(Box::new(Node::from(child)));
+        node
+    }
 
-                        InsertResult::Ok(None)
+    fn into_compact<O: TrieObserver>(mut self, observer: &mut O) -> Node<V> {
+        if self.value.is_some() {
+            let burst = match self.child {
+                Some(ref child) => {
+                    match **child {
+                        Node::Trie(_) => self.key.is_empty(),
+                        Node::Prefix(_) => false,
                     }
                 }
+                None => false,
+            };
+
+            if burst {
+                return Node::from(self.burst_into_trie(observer));
             }
-        }
-    }
 
-    fn get<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
-        println!("PrefixNode.get: self.key: {:?} other.key: {:?}", self.key, key);
+            return Node::from(self);
+        }
 
-        match self.find_difference(key) {
-            Some(_) => None,
-            None => {
-                if key.len() == self.key.len() {
-                    self.value.as_ref()
+        match self.child.take().map(|child| *child) {
+            Some(Node::Prefix(child)) => {
+                self.key.extend_from_slice(&child.key);
+                self.value = child.value;
+                self.child = child.child;
+                Node::from(self)
+            }
+            Some(Node::Trie(mut child)) => {
+                if self.key.is_empty() {
+                    Node::from(child)
                 } else {
-                    match self.child {
-                        Some(ref child) => child.get(&key[self.key.len()..]),
-                        None => None,
+                    // The value at the end of our key belongs to us rather than the trie.
+                    self.value = child.value.take();
+                    if self.value.is_some() {
+                        child.count -= 1;
                     }
+
+                    self.child = Some(Box::new(Node::from(child)));
+                    Node::from(self)
                 }
             }
+            None => Node::from(self),
         }
     }
 
-    fn split_front(&mut self) -> TrieNode<V> {
+    fn split_front<O: TrieObserver>(&mut self, observer: &mut O) -> TrieNode<V> {
         assert!(!self.key.is_empty());
 
+        observer.split_front(self.key.len());
+
         let mut value = None;
         mem::swap(&mut value, &mut self.value);
 
         let mut child = None;
         mem::swap(&mut child, &mut self.child);
 
+        self.count = 0;
+
         let byte = self.key[0];
-        let node = PrefixNode::with_child(self.key[1..].to_owned(), value, child);
+        let node = PrefixNode::with_child(self.key[1..].to_owned(), value, child)
+            .into_compact(observer);
+
+        // Compacting may hand back the trie node that was our child, rather than a new node.
+        if let Node::Prefix(_) = node {
+            observer.node_created(NodeKind::Prefix);
+        }
+
+        observer.node_created(NodeKind::Trie);
 
         let mut trie: TrieNode<V> = TrieNode::<V>::empty();
-        trie.children.insert(byte, Box::new(Node::from(node)));
+        trie.insert_child(byte, node);
 
         trie
     }
 
-    fn burst_into_trie(&mut self) -> TrieNode<V> {
+    fn burst_into_trie<O: TrieObserver>(&mut self, observer: &mut O) -> TrieNode<V> {
         // There are three cases that we need to handle when we burst a prefix node into a trie
         // node:
         //
@@ -473,116 +3778,279 @@ impl<V: Debug> PrefixNode<V> {
         // (3): The prefix is not empty. The prefix[1..] is cloned into a new prefix node, and is
         // inserted into the trie node.
 
+        observer.burst_into_trie();
+
+        if !self.key.is_empty() {
+            return self.split_front(observer);
+        }
+
         let mut self_value = None;
         mem::swap(&mut self_value, &mut self.value);
 
         let mut self_child = None;
         mem::swap(&mut self_child, &mut self.child);
 
-        if self.key.is_empty() {
-            match self_child {
-                Some(node) => {
-                    match *node {
-                        Node::Trie(node) => {
-                            assert!(!node.is_empty());
-                            assert!(node.value.is_none());
+        self.count = 0;
 
-                            node.value = self_value;
+        match self_child {
+            Some(node) => {
+                match *node {
+                    Node::Trie(mut node) => {
+                        assert!(!node.is_empty());
+                        assert!(node.value.is_none());
 
-                            node
-                        }
-                        Node::Prefix(node) => {
-                            node.split_front()
+                        if self_value.is_some() {
+                            node.count += 1;
                         }
+                        node.value = self_value;
+
+                        node
                     }
-                }
-                None => {
-                    let mut trie = TrieNode::empty();
-                    trie.value = self_value;
+                    Node::Prefix(mut node) => {
+                        let mut trie = node.split_front(observer);
+
+                        if self_value.is_some() {
+                            trie.count += 1;
+                        }
+                        trie.value = self_value;
 
-                    trie
+                        trie
+                    }
                 }
             }
-        } else {
-            self.split_front()
+            None => {
+                observer.node_created(NodeKind::Trie);
+                TrieNode::with_value(self_value)
+            }
         }
     }
 
-    /// Split the current node's key at position `pos`, and insert it and the current value into a
-    /// trie.
-    fn burst(&mut self, pos: usize) -> TrieNode<V> {
-        println!("PrefixNode.burst: pos: {}", pos);
-
+    /// Split the current node's key at position `pos`, and insert the byte at `pos`, the rest of the
+    /// key, and the current value and child into a trie. Our key is truncated to `key[..pos]`.
+    fn burst<O: TrieObserver>(&mut self, pos: usize, observer: &mut O) -> TrieNode<V> {
         let mut self_value = None;
         mem::swap(&mut self_value, &mut self.value);
 
         let mut self_child = None;
         mem::swap(&mut self_child, &mut self.child);
 
-        let mut trie = TrieNode::empty();
+        self.count = 0;
 
-        if self.key.is_empty() {
-            trie.value = self_value;
+        observer.node_created(NodeKind::Trie);
+
+        let trie = if self.key.is_empty() {
+            TrieNode::with_value(self_value)
         } else {
             let self_byte = self.key[pos];
-            println!("byte: {:?}", self_byte);
 
-            let node = Node::from(PrefixNode {
-                key: self.key[pos..].to_owned(),
-                value: self_value,
-                child: self_child,
-            });
+            let node = PrefixNode::with_child(self.key[pos + 1..].to_owned(),
+                                              self_value,
+                                              self_child).into_compact(observer);
+
+            if let Node::Prefix(_) = node {
+                observer.node_created(NodeKind::Prefix);
+            }
+
+            let mut trie = TrieNode::empty();
+            trie.insert_child(self_byte, node);
+            trie
+        };
 
-            println!("making node: {:?}", node);
+        self.key.truncate(pos);
 
-            trie.children.insert(self_byte, Box::new(node));
-        }
+        trie
+    }
 
-        println!("PrefixNode.burst2: {:?} {:?}",
-                 pos,
-                 self.key);
+    /// Burst the current node at the first differing position `pos`, and insert the key's suffix
+    /// alongside it.
+    fn burst_and_insert<O: TrieObserver>(&mut self,
+                                         pos: usize,
+                                         key: &[u8],
+                                         value: V,
+                                         observer: &mut O) -> TrieNode<V> {
+        let mut trie = self.burst(pos, observer);
 
-        self.key.truncate(pos + 1);
+        observer.node_created(NodeKind::Prefix);
+
+        let other_byte = key[pos];
+        let other_suffix_node = PrefixNode::new(key[pos + 1..].to_owned(), value);
 
-        println!("PrefixNode.burst3: {:?} {:?}",
-                 pos,
-                 self.key);
+        trie.insert_child(other_byte, Node::from(other_suffix_node));
 
         trie
     }
+}
 
-    /// Burst the current node 
-    fn burst_and_insert(&mut self, pos: usize, key: &[u8], value: V) -> TrieNode<V> {
-        println!("burst_and_insert: {:?} {:?} {:?}", pos, self.key, key);
-        println!("--");
-        println!("{:?}", self);
+impl<V> PrefixNode<V> {
+    fn iter(&self, key_len: usize) -> PrefixIter<&Node<V>> {
+        PrefixIter {
+            key_len: key_len,
+            value: self.value.as_ref(),
+            child: self.child.as_deref(),
+        }
+    }
 
-        let mut trie = self.burst(pos - 1);
+    fn iter_mut(&mut self, key_len: usize) -> PrefixIter<&mut Node<V>> {
+        PrefixIter {
+            key_len: key_len,
+            value: self.value.as_mut(),
+            child: self.child.as_deref_mut(),
+        }
+    }
 
-        println!("{:?}", trie);
+    fn into_iter(self, key_len: usize) -> PrefixIter<Box<Node<V>>> {
+        PrefixIter {
+            key_len: key_len,
+            value: self.value,
+            child: self.child,
+        }
+    }
+}
 
-        println!("");
+struct PrefixIter<H: IterHandle> {
+    key_len: usize,
+    value: Option<H::Value>,
+    child: Option<H>,
+}
 
-        let other_byte = key[pos];
-        let other_suffix_node = PrefixNode {
-            key: key[pos..].to_owned(),
-            value: Some(value),
-            child: None,
-        };
+/// How every key under a prefix node compares to a bound.
+enum Relation {
+    /// Every key is below the bound.
+    Below,
+    /// Every key is above the bound.
+    Above,
+    /// The node's key is the bound, and every key in its child is above it.
+    Equal,
+    /// The node's key is a proper prefix of the bound.
+    Within,
+}
 
-        println!("burst_and_insert2: {:?}", other_suffix_node);
-        println!("--\n");
+/// Escape a key so that it can be shown as text.
+fn escape(key: &[u8]) -> String {
+    let bytes: Vec<u8> = key.iter().flat_map(|byte| ascii::escape_default(*byte)).collect();
+    String::from_utf8(bytes).unwrap()
+}
 
-        trie.children.insert(other_byte, Box::new(Node::from(other_suffix_node)));
+/// Escape a label so that it can be quoted in a Graphviz file.
+fn escape_dot(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
 
-        trie
+fn is_included(bound: Bound<&[u8]>) -> bool {
+    match bound {
+        Bound::Included(_) => true,
+        Bound::Excluded(_) | Bound::Unbounded => false,
     }
 }
 
-/*
-struct PrefixIter<'a, V> {
-    key: Vec<u8>,
-    value: Option<&'a V>,
+/// Drop the first `len` bytes from a bound.
+fn bound_tail(bound: Bound<&[u8]>, len: usize) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(bound) => Bound::Included(&bound[len..]),
+        Bound::Excluded(bound) => Bound::Excluded(&bound[len..]),
+        Bound::Unbounded => Bound::Unbounded,
+    }
 }
-*/
 
+#[cfg(test)]
+mod tests {
+    use super::{InvariantError, Node, PrefixNode, TrieMap, TrieNode};
+
+    fn leaf(key: &[u8], value: u32) -> Node<u32> {
+        Node::Prefix(PrefixNode::new(key.to_vec(), value))
+    }
+
+    fn prefix(key: &[u8], value: Option<u32>, child: Option<Node<u32>>) -> Node<u32> {
+        Node::Prefix(PrefixNode::with_child(key.to_vec(), value, child.map(Box::new)))
+    }
+
+    fn trie(value: Option<u32>, children: Vec<(u8, Node<u32>)>) -> TrieNode<u32> {
+        let mut node = TrieNode::with_value(value);
+        for (byte, child) in children {
+            node.insert_child(byte, child);
+        }
+        node
+    }
+
+    /// Builds a map with `child` under the byte `a`, without going through `insert`.
+    fn map(child: Node<u32>) -> TrieMap<u32> {
+        let root = trie(None, vec![(b'a', child)]);
+
+        TrieMap {
+            len: root.count,
+            root: root,
+            observer: (),
+        }
+    }
+
+    fn pair() -> Node<u32> {
+        Node::Trie(trie(None, vec![(b'x', leaf(b"", 1)), (b'y', leaf(b"", 2))]))
+    }
+
+    #[test]
+    fn test_validate_valid() {
+        assert_eq!(map(leaf(b"bc", 1)).validate(), Ok(()));
+        assert_eq!(map(prefix(b"b", Some(1), Some(pair()))).validate(), Ok(()));
+        assert_eq!(map(prefix(b"b", Some(1), Some(leaf(b"c", 2)))).validate(), Ok(()));
+        assert_eq!(map(prefix(b"b", None, Some(pair()))).validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_errors() {
+        let mut broken = map(leaf(b"bc", 1));
+        broken.len = 2;
+        assert_eq!(broken.validate(), Err(InvariantError::Len(2, 1)));
+
+        let mut node = PrefixNode::new(b"bc".to_vec(), 1);
+        node.count = 2;
+        assert_eq!(map(Node::Prefix(node)).validate(),
+                   Err(InvariantError::Count(b"a".to_vec())));
+
+        let sparse = Node::Trie(trie(None, vec![(b'b', leaf(b"", 1))]));
+        assert_eq!(map(sparse).validate(), Err(InvariantError::SparseTrie(b"a".to_vec())));
+
+        assert_eq!(map(prefix(b"b", None, None)).validate(),
+                   Err(InvariantError::EmptyLeaf(b"a".to_vec())));
+
+        assert_eq!(map(prefix(b"", Some(1), Some(pair()))).validate(),
+                   Err(InvariantError::EmptyPrefix(b"a".to_vec())));
+
+        let valued = Node::Trie(trie(Some(3), vec![(b'x', leaf(b"", 1)), (b'y', leaf(b"", 2))]));
+        assert_eq!(map(prefix(b"b", None, Some(valued))).validate(),
+                   Err(InvariantError::TrieChildValue(b"a".to_vec())));
+
+        assert_eq!(map(prefix(b"b", None, Some(leaf(b"c", 1)))).validate(),
+                   Err(InvariantError::UnmergedPrefix(b"a".to_vec())));
+
+        assert_eq!(map(prefix(b"b", Some(1), Some(leaf(b"", 2)))).validate(),
+                   Err(InvariantError::EmptyPrefixChild(b"a".to_vec())));
+    }
+
+    #[test]
+    fn test_validate_nested_key() {
+        // Errors below a prefix node report the key through its compressed key.
+        let child = prefix(b"c", Some(1), Some(prefix(b"d", None, None)));
+        assert_eq!(map(prefix(b"b", Some(2), Some(child))).validate(),
+                   Err(InvariantError::EmptyLeaf(b"abc".to_vec())));
+    }
+
+    #[test]
+    #[cfg_attr(not(debug_assertions), ignore)]
+    #[should_panic(expected = "invalid trie")]
+    fn test_mutation_checks_invariants() {
+        let mut trie = map(leaf(b"bc", 1));
+        trie.len = 2;
+        trie.insert(b"d", 3);
+    }
+
+    #[test]
+    fn test_entry_checks_invariants() {
+        // The tests are built with the `check-invariants` feature, so in debug builds every insert
+        // through an entry is checked, and this panics if the entry leaves a broken node behind.
+        let mut trie = TrieMap::new();
+        for key in &[&b"abc"[..], b"abd", b"ab", b"a", b"", b"b", b"abcd"] {
+            trie.entry(key).or_insert(key.len());
+        }
+        assert_eq!(trie.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,57 @@
+/// The kind of a node in the trie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A node with a child for each byte that can come next.
+    Trie,
+    /// A node holding a run of bytes that every key below it shares, with at most one child.
+    Prefix,
+}
+
+/// Is told about the changes to the shape of a `TrieMap` as keys are inserted, merged or removed,
+/// so they can be logged or counted.
+///
+/// Every method does nothing by default, so an observer only needs to implement the events it
+/// cares about. A map without an observer uses `()`, which costs nothing.
+#[allow(unused_variables)]
+pub trait TrieObserver {
+    /// A node of the given kind was added to the trie.
+    fn node_created(&mut self, kind: NodeKind) {}
+
+    /// The compressed key of a prefix node, `len` bytes long, was split at `pos`. The node keeps
+    /// the first `pos` bytes, and the rest are moved into the nodes below it.
+    fn prefix_split(&mut self, len: usize, pos: usize) {}
+
+    /// A prefix node was replaced by a trie node.
+    fn burst_into_trie(&mut self) {}
+
+    /// The first byte of a prefix node's compressed key, `len` bytes long, was moved into a new
+    /// trie node above the rest of it.
+    fn split_front(&mut self, len: usize) {}
+
+    /// Inserting `key` replaced the value that was already stored for it.
+    fn value_replaced(&mut self, key: &[u8]) {}
+}
+
+impl TrieObserver for () {}
+
+impl<O: TrieObserver + ?Sized> TrieObserver for &mut O {
+    fn node_created(&mut self, kind: NodeKind) {
+        (**self).node_created(kind)
+    }
+
+    fn prefix_split(&mut self, len: usize, pos: usize) {
+        (**self).prefix_split(len, pos)
+    }
+
+    fn burst_into_trie(&mut self) {
+        (**self).burst_into_trie()
+    }
+
+    fn split_front(&mut self, len: usize) {
+        (**self).split_front(len)
+    }
+
+    fn value_replaced(&mut self, key: &[u8]) {
+        (**self).value_replaced(key)
+    }
+}
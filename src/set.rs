@@ -0,0 +1,418 @@
+use std::cmp::{self, Ordering};
+use std::iter;
+
+use map::{self, Cursor, TrieMap};
+
+/// A set of byte strings, stored as the keys of a `TrieMap`.
+#[derive(Debug, Clone)]
+pub struct TrieSet {
+    map: TrieMap<()>,
+}
+
+impl TrieSet {
+    pub fn new() -> Self {
+        TrieSet {
+            map: TrieMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Adds a key to the set. Returns true if the key was not already in the set.
+    pub fn insert(&mut self, key: &[u8]) -> bool {
+        self.map.insert(key, ()).is_none()
+    }
+
+    /// Returns true if the set contains `key`.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Removes a key from the set. Returns true if the key was in the set.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.map.remove(key).is_some()
+    }
+
+    /// Gets an iterator over the keys of the set, in sorted order.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter { inner: self.map.keys() }
+    }
+
+    /// Gets an iterator over the keys that are in `self` or `other`, in sorted order.
+    ///
+    /// The two sets are walked together, and a subtree that only one of them has keys in is
+    /// yielded whole without being compared against the other.
+    pub fn union<'a>(&'a self, other: &'a TrieSet) -> Union<'a> {
+        Union { inner: Merge::new(self, other, Op::Union) }
+    }
+
+    /// Gets an iterator over the keys that are in both `self` and `other`, in sorted order.
+    ///
+    /// The two sets are walked together, which skips every subtree that only one of them has keys
+    /// in.
+    pub fn intersection<'a>(&'a self, other: &'a TrieSet) -> Intersection<'a> {
+        Intersection { inner: Merge::new(self, other, Op::Intersection) }
+    }
+
+    /// Gets an iterator over the keys that are in `self` but not in `other`, in sorted order.
+    ///
+    /// The two sets are walked together. A subtree that only `self` has keys in is yielded whole,
+    /// and one that only `other` has keys in is skipped.
+    pub fn difference<'a>(&'a self, other: &'a TrieSet) -> Difference<'a> {
+        Difference { inner: Merge::new(self, other, Op::Difference) }
+    }
+
+    /// Gets an iterator over the keys that are in exactly one of `self` and `other`, in sorted
+    /// order.
+    ///
+    /// The two sets are walked together, and a subtree that only one of them has keys in is
+    /// yielded whole without being compared against the other.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a TrieSet) -> SymmetricDifference<'a> {
+        SymmetricDifference { inner: Merge::new(self, other, Op::SymmetricDifference) }
+    }
+
+    /// Returns true if every key in `self` is also in `other`.
+    pub fn is_subset(&self, other: &TrieSet) -> bool {
+        // Walk both sets together. Subtrees that only `other` has keys in are never visited, and
+        // the walk stops at the first subtree that holds more keys in `self` than in `other`.
+        let mut stack = vec![(self.map.cursor(), other.map.cursor())];
+
+        while let Some((lhs, rhs)) = stack.pop() {
+            if lhs.count() > rhs.count() || (lhs.value().is_some() && rhs.value().is_none()) {
+                return false;
+            }
+
+            for byte in lhs.next_bytes() {
+                let mut lhs_child = lhs.clone();
+                let mut rhs_child = rhs.clone();
+                lhs_child.step(byte);
+
+                if !rhs_child.step(byte) {
+                    return false;
+                }
+
+                stack.push((lhs_child, rhs_child));
+            }
+        }
+
+        true
+    }
+
+    /// Returns true if every key in `other` is also in `self`.
+    pub fn is_superset(&self, other: &TrieSet) -> bool {
+        other.is_subset(self)
+    }
+
+    /// Returns true if `self` and `other` have no keys in common.
+    pub fn is_disjoint(&self, other: &TrieSet) -> bool {
+        self.intersection(other).next().is_none()
+    }
+}
+
+impl Default for TrieSet {
+    fn default() -> Self {
+        TrieSet::new()
+    }
+}
+
+impl IntoIterator for TrieSet {
+    type Item = Vec<u8>;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        IntoIter { inner: self.map.into_iter() }
+    }
+}
+
+impl<'a> IntoIterator for &'a TrieSet {
+    type Item = Vec<u8>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> iter::FromIterator<&'a [u8]> for TrieSet {
+    fn from_iter<I: IntoIterator<Item=&'a [u8]>>(iterator: I) -> Self {
+        let mut set = TrieSet::new();
+        for key in iterator.into_iter() {
+            set.insert(key);
+        }
+        set
+    }
+}
+
+pub struct Iter<'a> {
+    inner: map::Keys<'a, ()>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Vec<u8>> {
+        self.inner.next_back()
+    }
+}
+
+pub struct IntoIter {
+    inner: map::IntoIter<()>,
+}
+
+impl Iterator for IntoIter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.inner.next().map(|(key, ())| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl ExactSizeIterator for IntoIter {}
+
+impl DoubleEndedIterator for IntoIter {
+    fn next_back(&mut self) -> Option<Vec<u8>> {
+        self.inner.next_back().map(|(key, ())| key)
+    }
+}
+
+/// Which keys a `Merge` yields.
+#[derive(Clone, Copy)]
+enum Op {
+    Union,
+    Intersection,
+    Difference,
+    SymmetricDifference,
+}
+
+impl Op {
+    /// Returns true if a key should be yielded, given whether each side holds it.
+    fn keeps(self, lhs: bool, rhs: bool) -> bool {
+        match self {
+            Op::Union => lhs || rhs,
+            Op::Intersection => lhs && rhs,
+            Op::Difference => lhs && !rhs,
+            Op::SymmetricDifference => lhs != rhs,
+        }
+    }
+}
+
+/// A position left to visit in a `Merge`.
+enum Frame<'a> {
+    /// A position that both sets reach. It holds the length of the key above it, and the byte
+    /// that leads to it from there, which is `None` at the root.
+    Both(Cursor<'a, ()>, Cursor<'a, ()>, usize, Option<u8>),
+    /// The keys below a position that only one of the sets reaches.
+    Graft(map::IterPrefix<'a, ()>),
+}
+
+/// Walks two sets together, one byte at a time, yielding the keys that `op` keeps in sorted
+/// order.
+///
+/// Only the positions that both sets reach are compared. Below any other position only one of
+/// the sets has keys, so that whole subtree is either yielded without looking at it again, or
+/// skipped.
+struct Merge<'a> {
+    op: Op,
+    key: Vec<u8>,
+    stack: Vec<Frame<'a>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(lhs: &'a TrieSet, rhs: &'a TrieSet, op: Op) -> Self {
+        Merge {
+            op: op,
+            key: Vec::new(),
+            stack: vec![Frame::Both(lhs.map.cursor(), rhs.map.cursor(), 0, None)],
+        }
+    }
+
+    /// Push the children of a position that both sets reach, so that they are popped in order.
+    fn push_children(&mut self, lhs: &Cursor<'a, ()>, rhs: &Cursor<'a, ()>) {
+        let mut lhs_bytes = lhs.next_bytes().peekable();
+        let mut rhs_bytes = rhs.next_bytes().peekable();
+        let mut frames = Vec::new();
+
+        loop {
+            let order = match (lhs_bytes.peek(), rhs_bytes.peek()) {
+                (Some(lhs), Some(rhs)) => lhs.cmp(rhs),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => { break; }
+            };
+
+            match order {
+                Ordering::Less => {
+                    let byte = lhs_bytes.next().unwrap();
+                    if self.op.keeps(true, false) {
+                        frames.push(self.graft(lhs, byte));
+                    }
+                }
+                Ordering::Greater => {
+                    let byte = rhs_bytes.next().unwrap();
+                    if self.op.keeps(false, true) {
+                        frames.push(self.graft(rhs, byte));
+                    }
+                }
+                Ordering::Equal => {
+                    let byte = lhs_bytes.next().unwrap();
+                    rhs_bytes.next();
+
+                    let mut lhs = lhs.clone();
+                    let mut rhs = rhs.clone();
+                    lhs.step(byte);
+                    rhs.step(byte);
+
+                    frames.push(Frame::Both(lhs, rhs, self.key.len(), Some(byte)));
+                }
+            }
+        }
+
+        self.stack.extend(frames.into_iter().rev());
+    }
+
+    fn graft(&self, cursor: &Cursor<'a, ()>, byte: u8) -> Frame<'a> {
+        let mut cursor = cursor.clone();
+        cursor.step(byte);
+
+        let mut key = self.key.clone();
+        key.push(byte);
+
+        Frame::Graft(cursor.iter_below(key))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let upper = self.stack
+            .iter()
+            .map(|frame| {
+                match *frame {
+                    Frame::Both(ref lhs, ref rhs, _, _) => {
+                        match self.op {
+                            Op::Union | Op::SymmetricDifference => lhs.count() + rhs.count(),
+                            Op::Intersection => cmp::min(lhs.count(), rhs.count()),
+                            Op::Difference => lhs.count(),
+                        }
+                    }
+                    Frame::Graft(ref iter) => iter.size_hint().1.unwrap_or(0),
+                }
+            })
+            .sum();
+
+        (0, Some(upper))
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (lhs, rhs) = match self.stack.pop() {
+                Some(Frame::Both(lhs, rhs, len, byte)) => {
+                    self.key.truncate(len);
+                    self.key.extend(byte);
+                    (lhs, rhs)
+                }
+                Some(Frame::Graft(mut iter)) => {
+                    if let Some((key, _)) = iter.next() {
+                        self.stack.push(Frame::Graft(iter));
+                        return Some(key);
+                    }
+                    continue;
+                }
+                None => { return None; }
+            };
+
+            self.push_children(&lhs, &rhs);
+
+            if self.op.keeps(lhs.value().is_some(), rhs.value().is_some()) {
+                return Some(self.key.clone());
+            }
+        }
+    }
+}
+
+pub struct Union<'a> {
+    inner: Merge<'a>,
+}
+
+impl<'a> Iterator for Union<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+pub struct Intersection<'a> {
+    inner: Merge<'a>,
+}
+
+impl<'a> Iterator for Intersection<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+pub struct Difference<'a> {
+    inner: Merge<'a>,
+}
+
+impl<'a> Iterator for Difference<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+pub struct SymmetricDifference<'a> {
+    inner: Merge<'a>,
+}
+
+impl<'a> Iterator for SymmetricDifference<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
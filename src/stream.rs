@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+
+use map::{Cursor, TrieMap};
+use observer::TrieObserver;
+
+/// Which matches a `StreamMatcher` reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchKind {
+    /// Every occurrence of every key, including ones that overlap.
+    All,
+    /// The longest key starting at the leftmost position that has a match, and then the same
+    /// again after the end of that match, so the matches never overlap.
+    Longest,
+}
+
+/// A key from the map found in the input, spanning the bytes `start..end` of the stream.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Match<'a, V: 'a> {
+    pub start: usize,
+    pub end: usize,
+    pub value: &'a V,
+}
+
+/// Finds the keys of a `TrieMap` in input that arrives in chunks, such as reads from a socket.
+///
+/// A key may be split across chunks. Rather than buffering the input, the matcher keeps a cursor
+/// into the trie for every position a key may still be starting at. Offsets are counted from the
+/// start of the stream. The empty key never matches.
+pub struct StreamMatcher<'a, V: 'a> {
+    /// A cursor at the root of the trie, which every new partial match starts from.
+    root: Cursor<'a, V>,
+    kind: MatchKind,
+    offset: usize,
+    partials: VecDeque<Partial<'a, V>>,
+}
+
+/// A key that may be starting at `start`.
+struct Partial<'a, V: 'a> {
+    start: usize,
+    cursor: Cursor<'a, V>,
+    /// Whether the cursor can still move any further.
+    alive: bool,
+    /// The longest match found from `start` so far.
+    longest: Option<(usize, &'a V)>,
+}
+
+impl<'a, V> StreamMatcher<'a, V> {
+    pub fn new<O: TrieObserver>(map: &'a TrieMap<V, O>, kind: MatchKind) -> Self {
+        StreamMatcher {
+            root: map.cursor(),
+            kind: kind,
+            offset: 0,
+            partials: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of bytes fed to the matcher so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Feeds the next chunk of the stream to the matcher, and returns the matches that were
+    /// completed by it, in the order they were decided.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Match<'a, V>> {
+        let mut matches = Vec::new();
+
+        for byte in chunk {
+            self.partials.push_back(Partial {
+                start: self.offset,
+                cursor: self.root.clone(),
+                alive: true,
+                longest: None,
+            });
+
+            self.offset += 1;
+
+            for partial in self.partials.iter_mut() {
+                if !partial.alive {
+                    continue;
+                }
+
+                if !partial.cursor.step(*byte) {
+                    partial.alive = false;
+                    continue;
+                }
+
+                if let Some(value) = partial.cursor.value() {
+                    match self.kind {
+                        MatchKind::All => {
+                            matches.push(Match {
+                                start: partial.start,
+                                end: self.offset,
+                                value: value,
+                            });
+                        }
+                        MatchKind::Longest => {
+                            partial.longest = Some((self.offset, value));
+                        }
+                    }
+                }
+
+                // Stop as soon as no key can go any further, so longest matches are reported
+                // without waiting for the next byte.
+                if partial.cursor.next_bytes().next().is_none() {
+                    partial.alive = false;
+                }
+            }
+
+            match self.kind {
+                MatchKind::All => self.partials.retain(|partial| partial.alive),
+                MatchKind::Longest => self.resolve(&mut matches),
+            }
+        }
+
+        matches
+    }
+
+    /// Ends the stream, and returns the longest matches that were still waiting to see if they
+    /// could be extended.
+    pub fn finish(mut self) -> Vec<Match<'a, V>> {
+        let mut matches = Vec::new();
+
+        for partial in self.partials.iter_mut() {
+            partial.alive = false;
+        }
+
+        if self.kind == MatchKind::Longest {
+            self.resolve(&mut matches);
+        }
+
+        matches
+    }
+
+    /// Report the longest matches that can no longer be beaten by an earlier or longer one.
+    fn resolve(&mut self, matches: &mut Vec<Match<'a, V>>) {
+        loop {
+            let (start, longest) = match self.partials.front() {
+                Some(partial) if !partial.alive => (partial.start, partial.longest),
+                _ => { return; }
+            };
+
+            match longest {
+                Some((end, value)) => {
+                    matches.push(Match {
+                        start: start,
+                        end: end,
+                        value: value,
+                    });
+
+                    // Drop every key that starts inside of the match.
+                    while self.partials.front().is_some_and(|partial| partial.start < end) {
+                        self.partials.pop_front();
+                    }
+                }
+                None => {
+                    self.partials.pop_front();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+#![allow(clippy::redundant_field_names)]
+
+use std::ops::Range;
+
+/// A linear congruential generator, so the randomised tests see the same keys on every run.
+pub struct Rng {
+    seed: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Rng {
+        Rng { seed: seed }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.seed = self.seed.wrapping_mul(1103515245).wrapping_add(12345);
+        self.seed
+    }
+
+    /// Returns a key whose length is drawn from `lens`, made of bytes drawn from `alphabet`.
+    pub fn key(&mut self, alphabet: &[u8], lens: Range<usize>) -> Vec<u8> {
+        let seed = self.next_u32();
+        let len = lens.start + (seed >> 16) as usize % (lens.end - lens.start);
+        (0..len)
+            .map(|j| alphabet[(seed >> (j * 2)) as usize % alphabet.len()])
+            .collect()
+    }
+}
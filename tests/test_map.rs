@@ -1,9 +1,18 @@
 //#![feature(test)]
+#![allow(clippy::redundant_field_names)]
 
 extern crate prefix_trie;
 //extern crate quickcheck;
 
+mod common;
+
+use std::fmt::Debug;
+
 use prefix_trie::TrieMap;
+use prefix_trie::map::{InvariantError, KeyRelation, Mismatch, MismatchKind, StopReason, TraceStep};
+use prefix_trie::observer::NodeKind;
+
+use common::Rng;
 
 #[test]
 fn test_empty() {
@@ -53,7 +62,7 @@ fn test_insert() {
 fn test_get() {
     let mut trie = TrieMap::new();
 
-    println!("");
+    println!();
 
     /*
     assert_eq!(trie.get(b""), None);
@@ -68,15 +77,15 @@ fn test_get() {
     assert_eq!(trie.get(b"ccc"), None);
     */
 
-    println!("");
+    println!();
 
     /*
     trie.insert(b"", 0);
-    println!("");
+    println!();
     trie.insert(b"a", 1);
-    println!("");
+    println!();
     trie.insert(b"aa", 2);
-    println!("");
+    println!();
     trie.insert(b"aaa", 3);
     trie.insert(b"b", 4);
     trie.insert(b"ab", 5);
@@ -84,22 +93,22 @@ fn test_get() {
     */
     trie.insert(b"cca", 7);
     println!("trie: {:?}", trie);
-    println!("");
+    println!();
     trie.insert(b"ccb", 8);
     println!("trie: {:?}", trie);
     /*
-    println!("");
+    println!();
     trie.insert(b"ccc", 9);
     println!("trie: {:?}", trie);
 
-    println!("");
+    println!();
     */
 
     /*
     assert_eq!(trie.get(b""), Some(&0));
-    println!("");
+    println!();
     assert_eq!(trie.get(b"a"), Some(&1));
-    println!("");
+    println!();
     assert_eq!(trie.get(b"aa"), Some(&2));
     assert_eq!(trie.get(b"aaa"), Some(&3));
     assert_eq!(trie.get(b"b"), Some(&4));
@@ -111,6 +120,1405 @@ fn test_get() {
     */
 }
 
+#[test]
+fn test_get_shorter_than_prefix() {
+    let mut trie = TrieMap::new();
+    trie.insert(b"abc", 1);
+    trie.insert(b"abcd", 2);
+
+    assert_eq!(trie.get(b""), None);
+    assert_eq!(trie.get(b"a"), None);
+    assert_eq!(trie.get(b"ab"), None);
+    assert_eq!(trie.get(b"abc"), Some(&1));
+    assert_eq!(trie.get(b"abcd"), Some(&2));
+    assert_eq!(trie.get(b"abcde"), None);
+    assert_eq!(trie.get_mut(b"ab"), None);
+}
+
+fn build_map() -> TrieMap<u32> {
+    let mut trie = TrieMap::new();
+    trie.insert(b"cca", 7);
+    trie.insert(b"aab", 6);
+    trie.insert(b"", 0);
+    trie.insert(b"ab", 5);
+    trie.insert(b"a", 1);
+    trie.insert(b"ccb", 8);
+    trie.insert(b"aaa", 3);
+    trie.insert(b"b", 4);
+    trie.insert(b"aa", 2);
+    trie.insert(b"ccc", 9);
+    trie
+}
+
+#[test]
+fn test_iter() {
+    let trie = TrieMap::<u32>::new();
+    assert_eq!(trie.iter().next(), None);
+
+    let trie = build_map();
+    let iter = trie.iter();
+    assert_eq!(iter.len(), 10);
+
+    let items: Vec<(Vec<u8>, u32)> = iter.map(|(key, value)| (key, *value)).collect();
+    assert_eq!(items, vec![
+        (b"".to_vec(), 0),
+        (b"a".to_vec(), 1),
+        (b"aa".to_vec(), 2),
+        (b"aaa".to_vec(), 3),
+        (b"aab".to_vec(), 6),
+        (b"ab".to_vec(), 5),
+        (b"b".to_vec(), 4),
+        (b"cca".to_vec(), 7),
+        (b"ccb".to_vec(), 8),
+        (b"ccc".to_vec(), 9),
+    ]);
+}
+
+#[test]
+fn test_iter_matches_btree_map() {
+    use std::collections::BTreeMap;
+
+    let mut trie = TrieMap::new();
+    let mut map = BTreeMap::new();
+
+    let mut rng = Rng::new(1);
+    for i in 0..2000 {
+        let key = rng.key(b"abc", 0..6);
+
+        assert_eq!(trie.insert(&key, i), map.insert(key.clone(), i));
+        assert_eq!(trie.len(), map.len());
+    }
+
+    let trie_items: Vec<(Vec<u8>, u32)> = trie.iter().map(|(key, value)| (key, *value)).collect();
+    let map_items: Vec<(Vec<u8>, u32)> = map.into_iter().collect();
+    assert_eq!(trie_items, map_items);
+
+    for (key, value) in &map_items {
+        assert_eq!(trie.get(key), Some(value));
+    }
+}
+
+#[test]
+fn test_iter_mut() {
+    let mut trie = build_map();
+
+    for (key, value) in trie.iter_mut() {
+        *value += key.len() as u32 * 10;
+    }
+
+    for value in trie.values_mut() {
+        *value += 100;
+    }
+
+    let values: Vec<u32> = trie.values().cloned().collect();
+    assert_eq!(values, vec![100, 111, 122, 133, 136, 125, 114, 137, 138, 139]);
+}
+
+#[test]
+fn test_keys_values() {
+    let trie = build_map();
+
+    let keys: Vec<Vec<u8>> = trie.keys().collect();
+    assert_eq!(keys, vec![
+        b"".to_vec(),
+        b"a".to_vec(),
+        b"aa".to_vec(),
+        b"aaa".to_vec(),
+        b"aab".to_vec(),
+        b"ab".to_vec(),
+        b"b".to_vec(),
+        b"cca".to_vec(),
+        b"ccb".to_vec(),
+        b"ccc".to_vec(),
+    ]);
+
+    let values: Vec<u32> = trie.values().cloned().collect();
+    assert_eq!(values, vec![0, 1, 2, 3, 6, 5, 4, 7, 8, 9]);
+}
+
+#[test]
+fn test_into_iter() {
+    let trie = build_map();
+    let expected: Vec<(Vec<u8>, u32)> = trie.iter().map(|(key, value)| (key, *value)).collect();
+
+    let iter = trie.into_iter();
+    assert_eq!(iter.len(), 10);
+    assert_eq!(iter.collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn test_drain() {
+    let mut trie = build_map();
+    let expected: Vec<(Vec<u8>, u32)> = trie.iter().map(|(key, value)| (key, *value)).collect();
+
+    assert_eq!(trie.drain().collect::<Vec<_>>(), expected);
+    assert!(trie.is_empty());
+    assert_eq!(trie.iter().next(), None);
+
+    trie.insert(b"a", 1);
+    assert_eq!(trie.len(), 1);
+}
+
+#[test]
+fn test_remove() {
+    let mut trie = build_map();
+
+    assert_eq!(trie.remove(b"aaaa"), None);
+    assert_eq!(trie.remove(b"c"), None);
+    assert_eq!(trie.remove(b"cc"), None);
+    assert_eq!(trie.len(), 10);
+
+    assert_eq!(trie.remove(b"aa"), Some(2));
+    assert_eq!(trie.remove(b"aa"), None);
+    assert_eq!(trie.len(), 9);
+
+    assert_eq!(trie.remove(b""), Some(0));
+    assert_eq!(trie.remove_entry(b"ccb"), Some((b"ccb".to_vec(), 8)));
+    assert_eq!(trie.remove_entry(b"ccb"), None);
+    assert_eq!(trie.len(), 7);
+
+    assert_eq!(trie.get(b"aaa"), Some(&3));
+    assert_eq!(trie.get(b"aab"), Some(&6));
+    assert_eq!(trie.get(b"cca"), Some(&7));
+    assert_eq!(trie.get(b"ccc"), Some(&9));
+
+    let keys: Vec<Vec<u8>> = trie.keys().collect();
+    assert_eq!(keys, vec![
+        b"a".to_vec(),
+        b"aaa".to_vec(),
+        b"aab".to_vec(),
+        b"ab".to_vec(),
+        b"b".to_vec(),
+        b"cca".to_vec(),
+        b"ccc".to_vec(),
+    ]);
+
+    for key in keys {
+        assert!(trie.remove(&key).is_some());
+    }
+
+    assert!(trie.is_empty());
+    assert_eq!(format!("{:?}", trie), format!("{:?}", TrieMap::<u32>::new()));
+}
+
+#[test]
+fn test_remove_compacts() {
+    let mut keys = Vec::new();
+
+    let mut rng = Rng::new(7);
+    for _ in 0..300 {
+        let key = rng.key(b"abc", 0..6);
+
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    let mut trie = TrieMap::new();
+    for key in &keys {
+        trie.insert(key, key.len());
+    }
+
+    // Remove every other key, and check that the result has the same shape as a map built from
+    // scratch, no matter which order that map was built in.
+    let mut remaining = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        if i % 2 == 0 {
+            assert_eq!(trie.remove(key), Some(key.len()));
+        } else {
+            remaining.push(key.clone());
+        }
+    }
+
+    let mut forward = TrieMap::new();
+    for key in &remaining {
+        forward.insert(key, key.len());
+    }
+
+    let mut backward = TrieMap::new();
+    for key in remaining.iter().rev() {
+        backward.insert(key, key.len());
+    }
+
+    assert_eq!(trie.len(), forward.len());
+    assert_eq!(format!("{:?}", trie), format!("{:?}", forward));
+    assert_eq!(format!("{:?}", trie), format!("{:?}", backward));
+}
+
+#[test]
+fn test_entry() {
+    use prefix_trie::map::Entry;
+
+    let mut trie = build_map();
+
+    assert_eq!(*trie.entry(b"aa").or_insert(20), 2);
+    assert_eq!(*trie.entry(b"ac").or_insert(20), 20);
+    assert_eq!(trie.len(), 11);
+
+    *trie.entry(b"cc").or_insert_with(|| 30) += 1;
+    assert_eq!(trie.get(b"cc"), Some(&31));
+
+    trie.entry(b"cc").and_modify(|value| *value += 1).or_insert(0);
+    trie.entry(b"cd").and_modify(|value| *value += 1).or_insert(40);
+    assert_eq!(trie.get(b"cc"), Some(&32));
+    assert_eq!(trie.get(b"cd"), Some(&40));
+
+    assert_eq!(*trie.entry(b"ccca").or_default(), 0);
+    assert_eq!(trie.len(), 14);
+
+    match trie.entry(b"b") {
+        Entry::Occupied(mut entry) => {
+            assert_eq!(entry.key(), b"b");
+            assert_eq!(entry.insert(50), 4);
+            assert_eq!(*entry.get(), 50);
+        }
+        Entry::Vacant(_) => panic!("expected an occupied entry"),
+    }
+
+    match trie.entry(b"bb") {
+        Entry::Occupied(_) => panic!("expected a vacant entry"),
+        Entry::Vacant(entry) => {
+            assert_eq!(entry.key(), b"bb");
+            assert_eq!(*entry.insert(60), 60);
+        }
+    }
+
+    assert_eq!(trie.get(b"b"), Some(&50));
+    assert_eq!(trie.get(b"bb"), Some(&60));
+    assert_eq!(trie.len(), 15);
+}
+
+#[test]
+fn test_entry_matches_insert() {
+    let mut inserted = TrieMap::new();
+    let mut entered = TrieMap::new();
+
+    let mut rng = Rng::new(3);
+    for i in 0..1000 {
+        let key = rng.key(b"abc", 0..6);
+
+        inserted.insert(&key, i);
+        *entered.entry(&key).or_insert(i) = i;
+
+        assert_eq!(entered.len(), inserted.len());
+    }
+
+    assert_eq!(format!("{:?}", entered), format!("{:?}", inserted));
+}
+
+#[test]
+fn test_iter_prefix() {
+    let mut trie = build_map();
+    trie.insert(b"ccccdd", 10);
+    trie.insert(b"ccccde", 11);
+
+    fn keys(trie: &TrieMap<u32>, prefix: &[u8]) -> Vec<Vec<u8>> {
+        trie.iter_prefix(prefix).map(|(key, _)| key).collect()
+    }
+
+    assert_eq!(keys(&trie, b""), trie.keys().collect::<Vec<_>>());
+    assert_eq!(keys(&trie, b"a"), vec![
+        b"a".to_vec(),
+        b"aa".to_vec(),
+        b"aaa".to_vec(),
+        b"aab".to_vec(),
+        b"ab".to_vec(),
+    ]);
+    assert_eq!(keys(&trie, b"aa"), vec![b"aa".to_vec(), b"aaa".to_vec(), b"aab".to_vec()]);
+    assert_eq!(keys(&trie, b"aab"), vec![b"aab".to_vec()]);
+    assert_eq!(keys(&trie, b"aabb"), Vec::<Vec<u8>>::new());
+    assert_eq!(keys(&trie, b"b"), vec![b"b".to_vec()]);
+    assert_eq!(keys(&trie, b"d"), Vec::<Vec<u8>>::new());
+
+    // Prefixes that end in the middle of a compressed key.
+    assert_eq!(keys(&trie, b"c"), vec![
+        b"cca".to_vec(),
+        b"ccb".to_vec(),
+        b"ccc".to_vec(),
+        b"ccccdd".to_vec(),
+        b"ccccde".to_vec(),
+    ]);
+    assert_eq!(keys(&trie, b"cccc"), vec![b"ccccdd".to_vec(), b"ccccde".to_vec()]);
+    assert_eq!(keys(&trie, b"ccccd"), vec![b"ccccdd".to_vec(), b"ccccde".to_vec()]);
+    assert_eq!(keys(&trie, b"cccce"), Vec::<Vec<u8>>::new());
+    assert_eq!(keys(&trie, b"ccccdef"), Vec::<Vec<u8>>::new());
+
+    let values: Vec<u32> = trie.iter_prefix(b"ccc").map(|(_, value)| *value).collect();
+    assert_eq!(values, vec![9, 10, 11]);
+}
+
+#[test]
+fn test_longest_prefix_match() {
+    let mut trie = TrieMap::new();
+    assert_eq!(trie.longest_prefix_match(b"abc"), None);
+
+    trie.insert(b"/usr", 1);
+    trie.insert(b"/usr/local", 2);
+    trie.insert(b"/usr/local/bin", 3);
+    trie.insert(b"/var", 4);
+
+    assert_eq!(trie.longest_prefix_match(b""), None);
+    assert_eq!(trie.longest_prefix_match(b"/"), None);
+    assert_eq!(trie.longest_prefix_match(b"/us"), None);
+    assert_eq!(trie.longest_prefix_match(b"/usr"), Some((4, &1)));
+    assert_eq!(trie.longest_prefix_match(b"/usr/"), Some((4, &1)));
+    assert_eq!(trie.longest_prefix_match(b"/usr/loc"), Some((4, &1)));
+    assert_eq!(trie.longest_prefix_match(b"/usr/local"), Some((10, &2)));
+    assert_eq!(trie.longest_prefix_match(b"/usr/local/b"), Some((10, &2)));
+    assert_eq!(trie.longest_prefix_match(b"/usr/local/bin/ls"), Some((14, &3)));
+    assert_eq!(trie.longest_prefix_match(b"/usr/share"), Some((4, &1)));
+    assert_eq!(trie.longest_prefix_match(b"/var/log"), Some((4, &4)));
+    assert_eq!(trie.longest_prefix_match(b"/vat"), None);
+
+    trie.insert(b"", 0);
+    assert_eq!(trie.longest_prefix_match(b"/vat"), Some((0, &0)));
+    assert_eq!(trie.longest_prefix_match(b"/usr/local/lib"), Some((10, &2)));
+
+    // The value borrows from the map, not from the key it was looked up with.
+    let value = {
+        let key = b"/usr/local/bin/ls".to_vec();
+        trie.longest_prefix_match(&key)
+    };
+    assert_eq!(value, Some((14, &3)));
+}
+
+#[test]
+fn test_common_prefixes() {
+    let mut trie = TrieMap::new();
+    assert_eq!(trie.common_prefixes(b"abc").next(), None);
+
+    trie.insert(b"a", 1);
+    trie.insert(b"ab", 2);
+    trie.insert(b"abcd", 3);
+    trie.insert(b"abce", 4);
+    trie.insert(b"b", 5);
+
+    fn prefixes(trie: &TrieMap<u32>, key: &[u8]) -> Vec<(usize, u32)> {
+        trie.common_prefixes(key).map(|(len, value)| (len, *value)).collect()
+    }
+
+    assert_eq!(prefixes(&trie, b""), vec![]);
+    assert_eq!(prefixes(&trie, b"a"), vec![(1, 1)]);
+    assert_eq!(prefixes(&trie, b"abc"), vec![(1, 1), (2, 2)]);
+    assert_eq!(prefixes(&trie, b"abcd"), vec![(1, 1), (2, 2), (4, 3)]);
+    assert_eq!(prefixes(&trie, b"abcdef"), vec![(1, 1), (2, 2), (4, 3)]);
+    assert_eq!(prefixes(&trie, b"abcf"), vec![(1, 1), (2, 2)]);
+    assert_eq!(prefixes(&trie, b"ba"), vec![(1, 5)]);
+    assert_eq!(prefixes(&trie, b"c"), vec![]);
+
+    trie.insert(b"", 0);
+    assert_eq!(prefixes(&trie, b"abce"), vec![(0, 0), (1, 1), (2, 2), (4, 4)]);
+
+    let values: Vec<(usize, &u32)> = {
+        let key = b"abcd".to_vec();
+        trie.common_prefixes(&key).collect()
+    };
+    assert_eq!(values, vec![(0, &0), (1, &1), (2, &2), (4, &3)]);
+}
+
+#[test]
+fn test_range() {
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let trie = build_map();
+
+    fn keys<'a, I: Iterator<Item=(Vec<u8>, &'a u32)>>(iter: I) -> Vec<Vec<u8>> {
+        iter.map(|(key, _)| key).collect()
+    }
+
+    assert_eq!(keys(trie.range(..)), trie.keys().collect::<Vec<_>>());
+    assert_eq!(keys(trie.range((Included(&b"aa"[..]), Excluded(&b"b"[..])))), vec![
+        b"aa".to_vec(),
+        b"aaa".to_vec(),
+        b"aab".to_vec(),
+        b"ab".to_vec(),
+    ]);
+    assert_eq!(keys(trie.range((Included(&b"aab"[..]), Included(&b"b"[..])))), vec![
+        b"aab".to_vec(),
+        b"ab".to_vec(),
+        b"b".to_vec(),
+    ]);
+    assert_eq!(keys(trie.range((Excluded(&b"cc"[..]), Included(&b"ccb"[..])))), vec![
+        b"cca".to_vec(),
+        b"ccb".to_vec(),
+    ]);
+    assert_eq!(keys(trie.range((Included(&b"ccc"[..]), Unbounded))), vec![b"ccc".to_vec()]);
+    assert_eq!(keys(trie.range((Excluded(&b"ccc"[..]), Unbounded))), Vec::<Vec<u8>>::new());
+    assert_eq!(keys(trie.range((Included(&b"b"[..]), Excluded(&b"a"[..])))), Vec::<Vec<u8>>::new());
+
+    // The standard range syntax works with byte slices, and with owned keys.
+    let (aa, b, cc): (&[u8], &[u8], &[u8]) = (b"aa", b"b", b"cc");
+    assert_eq!(keys(trie.range(aa..b)), keys(trie.range((Included(aa), Excluded(b)))));
+    assert_eq!(keys(trie.range(aa..=b)), keys(trie.range((Included(aa), Included(b)))));
+    assert_eq!(keys(trie.range(cc..)), keys(trie.range((Included(cc), Unbounded))));
+    assert_eq!(keys(trie.range(..b)), keys(trie.range((Unbounded, Excluded(b)))));
+    assert_eq!(keys(trie.range(..=b)), keys(trie.range((Unbounded, Included(b)))));
+    assert_eq!(keys(trie.range(b"aab".to_vec()..b"b".to_vec())), vec![
+        b"aab".to_vec(),
+        b"ab".to_vec(),
+    ]);
+    assert_eq!(keys(trie.range(b..aa)), Vec::<Vec<u8>>::new());
+}
+
+#[test]
+fn test_range_matches_btree_map() {
+    use std::collections::BTreeMap;
+    use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+    let mut trie = TrieMap::new();
+    let mut map = BTreeMap::new();
+
+    let mut rng = Rng::new(5);
+    for i in 0..200 {
+        let key = rng.key(b"abc", 0..5);
+
+        trie.insert(&key, i);
+        map.insert(key, i);
+    }
+
+    // Every key of up to two bytes, including a byte that is never stored.
+    let mut bounds = vec![Vec::new()];
+    for len in 1..3 {
+        let mut key = vec![b'a'; len];
+        loop {
+            bounds.push(key.clone());
+
+            match key.iter().rposition(|&byte| byte != b'd') {
+                Some(pos) => {
+                    key[pos] += 1;
+                    for byte in &mut key[pos + 1..] {
+                        *byte = b'a';
+                    }
+                }
+                None => { break; }
+            }
+        }
+    }
+
+    fn bound(kind: usize, key: &[u8]) -> Bound<&[u8]> {
+        match kind {
+            0 => Included(key),
+            1 => Excluded(key),
+            _ => Unbounded,
+        }
+    }
+
+    for start in &bounds {
+        for end in &bounds {
+            for start_kind in 0..3 {
+                for end_kind in 0..3 {
+                    let range = (bound(start_kind, start), bound(end_kind, end));
+
+                    let trie_items: Vec<(Vec<u8>, u32)> = trie.range(range)
+                        .map(|(key, value)| (key, *value))
+                        .collect();
+
+                    // `BTreeMap` panics on these empty ranges.
+                    let bounded = start_kind < 2 && end_kind < 2;
+                    let inverted = start > end || (start == end && start_kind == 1 && end_kind == 1);
+
+                    let rev_items: Vec<(Vec<u8>, u32)> = trie.range(range)
+                        .rev()
+                        .map(|(key, value)| (key, *value))
+                        .collect();
+
+                    // Take from alternating ends, and stitch the two halves back together.
+                    let mut iter = trie.range(range);
+                    let mut front = Vec::new();
+                    let mut back = Vec::new();
+                    loop {
+                        let item = if (front.len() + back.len()) % 2 == 0 {
+                            iter.next().map(|item| front.push(item))
+                        } else {
+                            iter.next_back().map(|item| back.push(item))
+                        };
+                        if item.is_none() {
+                            break;
+                        }
+                    }
+                    back.reverse();
+                    front.extend(back);
+                    let both_items: Vec<(Vec<u8>, u32)> = front.into_iter()
+                        .map(|(key, value)| (key, *value))
+                        .collect();
+                    assert_eq!(both_items, trie_items);
+
+                    if bounded && inverted {
+                        assert_eq!(trie_items, vec![]);
+                        assert_eq!(rev_items, vec![]);
+                    } else {
+                        let map_items: Vec<(Vec<u8>, u32)> = map.range::<[u8], _>(range)
+                            .map(|(key, value)| (key.clone(), *value))
+                            .collect();
+                        assert_eq!(trie_items, map_items);
+
+                        let map_rev_items: Vec<(Vec<u8>, u32)> = map.range::<[u8], _>(range)
+                            .rev()
+                            .map(|(key, value)| (key.clone(), *value))
+                            .collect();
+                        assert_eq!(rev_items, map_rev_items);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_iter_rev() {
+    let mut trie = build_map();
+
+    let forward: Vec<(Vec<u8>, u32)> = trie.iter().map(|(key, value)| (key, *value)).collect();
+    let mut backward: Vec<(Vec<u8>, u32)> = trie.iter()
+        .rev()
+        .map(|(key, value)| (key, *value))
+        .collect();
+    backward.reverse();
+    assert_eq!(forward, backward);
+
+    let keys: Vec<Vec<u8>> = trie.keys().rev().collect();
+    assert_eq!(keys[0], b"ccc".to_vec());
+    assert_eq!(keys[9], b"".to_vec());
+
+    for value in trie.values_mut().rev().take(3) {
+        *value += 10;
+    }
+    let values: Vec<u32> = trie.values().rev().cloned().collect();
+    assert_eq!(values, vec![19, 18, 17, 4, 5, 6, 3, 2, 1, 0]);
+
+    let iter_prefix: Vec<Vec<u8>> = trie.iter_prefix(b"a").rev().map(|(key, _)| key).collect();
+    assert_eq!(iter_prefix, vec![
+        b"ab".to_vec(),
+        b"aab".to_vec(),
+        b"aaa".to_vec(),
+        b"aa".to_vec(),
+        b"a".to_vec(),
+    ]);
+
+    let into_iter: Vec<Vec<u8>> = trie.into_iter().rev().map(|(key, _)| key).collect();
+    assert_eq!(into_iter, keys);
+}
+
+#[test]
+fn test_iter_both_ends() {
+    use std::collections::BTreeMap;
+
+    let mut trie = TrieMap::new();
+    let mut map = BTreeMap::new();
+
+    let mut rng = Rng::new(11);
+    for i in 0..300 {
+        let key = rng.key(b"abc", 0..6);
+
+        trie.insert(&key, i);
+        map.insert(key, i);
+    }
+
+    // Alternate between the two ends of the iterators in a few different patterns.
+    for pattern in 1..8 {
+        let mut trie_iter = trie.iter_mut();
+        let mut map_iter = map.iter_mut();
+        let mut step = 0;
+
+        loop {
+            let (trie_item, map_item) = if (step / pattern) % 2 == 0 {
+                (trie_iter.next(), map_iter.next())
+            } else {
+                (trie_iter.next_back(), map_iter.next_back())
+            };
+            step += 1;
+
+            assert_eq!(trie_iter.len(), map_iter.len());
+
+            match (trie_item, map_item) {
+                (Some((trie_key, trie_value)), Some((map_key, map_value))) => {
+                    assert_eq!(&trie_key, map_key);
+                    assert_eq!(trie_value, map_value);
+                }
+                (None, None) => { break; }
+                (trie_item, map_item) => {
+                    panic!("{:?} != {:?}", trie_item, map_item);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_first_last() {
+    let mut trie = build_map();
+
+    assert_eq!(trie.first_key_value(), Some((b"".to_vec(), &0)));
+    assert_eq!(trie.last_key_value(), Some((b"ccc".to_vec(), &9)));
+    assert_eq!(trie.iter_prefix(b"aa").next_back(), Some((b"aab".to_vec(), &6)));
+
+    assert_eq!(trie.pop_first(), Some((b"".to_vec(), 0)));
+    assert_eq!(trie.pop_first(), Some((b"a".to_vec(), 1)));
+    assert_eq!(trie.pop_last(), Some((b"ccc".to_vec(), 9)));
+    assert_eq!(trie.pop_last(), Some((b"ccb".to_vec(), 8)));
+    assert_eq!(trie.len(), 6);
+
+    assert_eq!(trie.first_key_value(), Some((b"aa".to_vec(), &2)));
+    assert_eq!(trie.last_key_value(), Some((b"cca".to_vec(), &7)));
+
+    while trie.pop_last().is_some() {}
+    assert!(trie.is_empty());
+    assert_eq!(trie.first_key_value(), None);
+    assert_eq!(trie.last_key_value(), None);
+    assert_eq!(trie.pop_first(), None);
+}
+
+#[test]
+fn test_bounds() {
+    let mut trie = TrieMap::new();
+    assert_eq!(trie.lower_bound(b"a"), None);
+    assert_eq!(trie.upper_bound(b"a"), None);
+
+    trie.insert(b"apple", 1);
+    trie.insert(b"apricot", 2);
+    trie.insert(b"banana", 3);
+    trie.insert(b"band", 4);
+
+    assert_eq!(trie.lower_bound(b"apple"), Some((b"apple".to_vec(), &1)));
+    assert_eq!(trie.upper_bound(b"apple"), Some((b"apple".to_vec(), &1)));
+
+    // Diverging inside a compressed key.
+    assert_eq!(trie.lower_bound(b"apq"), Some((b"apple".to_vec(), &1)));
+    assert_eq!(trie.upper_bound(b"apq"), Some((b"apricot".to_vec(), &2)));
+    assert_eq!(trie.lower_bound(b"apricox"), Some((b"apricot".to_vec(), &2)));
+    assert_eq!(trie.upper_bound(b"apricos"), Some((b"apricot".to_vec(), &2)));
+
+    // Ending inside, or running past, a compressed key.
+    assert_eq!(trie.lower_bound(b"appl"), None);
+    assert_eq!(trie.upper_bound(b"appl"), Some((b"apple".to_vec(), &1)));
+    assert_eq!(trie.lower_bound(b"apples"), Some((b"apple".to_vec(), &1)));
+    assert_eq!(trie.upper_bound(b"apples"), Some((b"apricot".to_vec(), &2)));
+    assert_eq!(trie.lower_bound(b"banc"), Some((b"banana".to_vec(), &3)));
+    assert_eq!(trie.upper_bound(b"banc"), Some((b"band".to_vec(), &4)));
+
+    assert_eq!(trie.lower_bound(b""), None);
+    assert_eq!(trie.upper_bound(b""), Some((b"apple".to_vec(), &1)));
+    assert_eq!(trie.lower_bound(b"z"), Some((b"band".to_vec(), &4)));
+    assert_eq!(trie.upper_bound(b"z"), None);
+}
+
+#[test]
+fn test_rank_select() {
+    let trie = build_map();
+
+    assert_eq!(trie.rank(b""), 0);
+    assert_eq!(trie.rank(b"aa"), 2);
+    assert_eq!(trie.rank(b"aab"), 4);
+    assert_eq!(trie.rank(b"b"), 6);
+    assert_eq!(trie.rank(b"cc"), 7);
+    assert_eq!(trie.rank(b"ccbz"), 9);
+    assert_eq!(trie.rank(b"z"), 10);
+
+    assert_eq!(trie.select(0), Some(b"".to_vec()));
+    assert_eq!(trie.select(4), Some(b"aab".to_vec()));
+    assert_eq!(trie.nth(9), Some((b"ccc".to_vec(), &9)));
+    assert_eq!(trie.nth(10), None);
+
+    assert_eq!(trie.count_prefix(b""), 10);
+    assert_eq!(trie.count_prefix(b"a"), 5);
+    assert_eq!(trie.count_prefix(b"aa"), 3);
+    assert_eq!(trie.count_prefix(b"c"), 3);
+    assert_eq!(trie.count_prefix(b"ccb"), 1);
+    assert_eq!(trie.count_prefix(b"ccd"), 0);
+    assert_eq!(trie.count_prefix(b"d"), 0);
+}
+
+#[test]
+fn test_rank_select_matches_btree_map() {
+    use std::collections::BTreeMap;
+
+    let mut trie = TrieMap::new();
+    let mut map = BTreeMap::new();
+
+    let mut rng = Rng::new(11);
+    for i in 0..2000 {
+        let key = rng.key(b"abc", 0..6);
+
+        // Mix inserts through the entry API with removals, so the subtree counts have to survive
+        // both bursting and compacting nodes.
+        match rng.next_u32() % 3 {
+            0 => { assert_eq!(trie.remove(&key), map.remove(&key)); }
+            1 => { trie.entry(&key).or_insert(i); map.entry(key.clone()).or_insert(i); }
+            _ => { assert_eq!(trie.insert(&key, i), map.insert(key.clone(), i)); }
+        }
+
+        if i % 50 == 0 {
+            for (n, (key, value)) in map.iter().enumerate() {
+                assert_eq!(trie.rank(key), n);
+                assert_eq!(trie.nth(n), Some((key.clone(), value)));
+            }
+
+            assert_eq!(trie.select(map.len()), None);
+
+            for len in 0..4 {
+                for probe in 0..3usize.pow(len) {
+                    let probe: Vec<u8> = (0..len)
+                        .map(|j| b"abc"[probe / 3usize.pow(j) % 3])
+                        .collect();
+
+                    assert_eq!(trie.rank(&probe), map.range(..probe.clone()).count());
+                    assert_eq!(trie.count_prefix(&probe),
+                               map.keys().filter(|key| key.starts_with(&probe)).count());
+                }
+            }
+        }
+    }
+}
+
+fn random_map(seed: u32, n: usize) -> TrieMap<usize> {
+    let mut trie = TrieMap::new();
+
+    let mut rng = Rng::new(seed);
+    for i in 0..n {
+        trie.insert(&rng.key(b"abc", 0..6), i);
+    }
+
+    trie
+}
+
+/// Rebuilds a map from its entries, which gives the shape the map should have.
+fn rebuild<V: Clone + Debug>(trie: &TrieMap<V>) -> TrieMap<V> {
+    let mut rebuilt = TrieMap::new();
+    for (key, value) in trie.iter() {
+        rebuilt.insert(&key, value.clone());
+    }
+    rebuilt
+}
+
+#[test]
+fn test_split_off() {
+    let mut trie = build_map();
+    let right = trie.split_off(b"ab");
+
+    assert_eq!(trie.keys().collect::<Vec<_>>(), vec![
+        b"".to_vec(),
+        b"a".to_vec(),
+        b"aa".to_vec(),
+        b"aaa".to_vec(),
+        b"aab".to_vec(),
+    ]);
+    assert_eq!(right.keys().collect::<Vec<_>>(), vec![
+        b"ab".to_vec(),
+        b"b".to_vec(),
+        b"cca".to_vec(),
+        b"ccb".to_vec(),
+        b"ccc".to_vec(),
+    ]);
+    assert_eq!(trie.len(), 5);
+    assert_eq!(right.len(), 5);
+
+    for len in 0..4 {
+        for probe in 0..3usize.pow(len) {
+            let probe: Vec<u8> = (0..len)
+                .map(|j| b"abc"[probe / 3usize.pow(j) % 3])
+                .collect();
+
+            let mut left = random_map(5, 200);
+            let expected = left.iter().map(|(key, value)| (key, *value)).collect::<Vec<_>>();
+            let right = left.split_off(&probe);
+
+            assert!(left.keys().all(|key| key < probe));
+            assert!(right.keys().all(|key| key >= probe));
+            assert_eq!(left.iter().chain(right.iter())
+                           .map(|(key, value)| (key, *value))
+                           .collect::<Vec<_>>(),
+                       expected);
+            assert_eq!(left.len() + right.len(), expected.len());
+
+            assert_eq!(format!("{:?}", left), format!("{:?}", rebuild(&left)));
+            assert_eq!(format!("{:?}", right), format!("{:?}", rebuild(&right)));
+        }
+    }
+}
+
+#[test]
+fn test_split_off_prefix() {
+    let mut trie = build_map();
+    trie.insert(b"ccccdd", 10);
+
+    let cc = trie.split_off_prefix(b"cc");
+    assert_eq!(cc.keys().collect::<Vec<_>>(), vec![
+        b"cca".to_vec(),
+        b"ccb".to_vec(),
+        b"ccc".to_vec(),
+        b"ccccdd".to_vec(),
+    ]);
+    assert_eq!(trie.count_prefix(b"c"), 0);
+    assert_eq!(trie.len(), 7);
+
+    assert_eq!(trie.split_off_prefix(b"d").len(), 0);
+    assert_eq!(trie.split_off_prefix(b"aab").keys().collect::<Vec<_>>(), vec![b"aab".to_vec()]);
+    assert_eq!(trie.len(), 6);
+
+    for len in 0..4 {
+        for probe in 0..3usize.pow(len) {
+            let probe: Vec<u8> = (0..len)
+                .map(|j| b"abc"[probe / 3usize.pow(j) % 3])
+                .collect();
+
+            let mut rest = random_map(7, 200);
+            let expected = rest.iter()
+                .filter(|(key, _)| key.starts_with(&probe))
+                .map(|(key, value)| (key, *value))
+                .collect::<Vec<_>>();
+            let len = rest.len();
+
+            let split = rest.split_off_prefix(&probe);
+
+            assert_eq!(split.iter().map(|(key, value)| (key, *value)).collect::<Vec<_>>(),
+                       expected);
+            assert_eq!(rest.count_prefix(&probe), 0);
+            assert_eq!(rest.len() + split.len(), len);
+
+            assert_eq!(format!("{:?}", rest), format!("{:?}", rebuild(&rest)));
+            assert_eq!(format!("{:?}", split), format!("{:?}", rebuild(&split)));
+        }
+    }
+}
+
+#[test]
+fn test_remove_prefix() {
+    let mut trie = build_map();
+
+    assert_eq!(trie.remove_prefix(b"d"), 0);
+    assert_eq!(trie.remove_prefix(b"aa"), 3);
+    assert_eq!(trie.len(), 7);
+    assert_eq!(trie.keys().collect::<Vec<_>>(), vec![
+        b"".to_vec(),
+        b"a".to_vec(),
+        b"ab".to_vec(),
+        b"b".to_vec(),
+        b"cca".to_vec(),
+        b"ccb".to_vec(),
+        b"ccc".to_vec(),
+    ]);
+
+    // A prefix that ends in the middle of a compressed key.
+    assert_eq!(trie.remove_prefix(b"c"), 3);
+    assert_eq!(format!("{:?}", trie), format!("{:?}", rebuild(&trie)));
+
+    assert_eq!(trie.remove_prefix(b""), 4);
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn test_retain() {
+    let mut trie = build_map();
+    trie.retain(|key, value| {
+        *value += 10;
+        key.len() != 2
+    });
+
+    assert_eq!(trie.iter().map(|(key, value)| (key, *value)).collect::<Vec<_>>(), vec![
+        (b"".to_vec(), 10),
+        (b"a".to_vec(), 11),
+        (b"aaa".to_vec(), 13),
+        (b"aab".to_vec(), 16),
+        (b"b".to_vec(), 14),
+        (b"cca".to_vec(), 17),
+        (b"ccb".to_vec(), 18),
+        (b"ccc".to_vec(), 19),
+    ]);
+    assert_eq!(trie.len(), 8);
+
+    for seed in 0..10 {
+        let mut trie = random_map(seed, 200);
+        let expected = trie.iter()
+            .filter(|&(_, value)| value % 3 != 0)
+            .map(|(key, value)| (key, *value))
+            .collect::<Vec<_>>();
+
+        trie.retain(|_, value| *value % 3 != 0);
+
+        assert_eq!(trie.iter().map(|(key, value)| (key, *value)).collect::<Vec<_>>(), expected);
+        assert_eq!(trie.len(), expected.len());
+        assert_eq!(format!("{:?}", trie), format!("{:?}", rebuild(&trie)));
+    }
+}
+
+#[test]
+fn test_retain_prefixes() {
+    use prefix_trie::map::Retain;
+
+    let mut trie = build_map();
+    trie.insert(b"ccccdd", 10);
+
+    let mut asked = Vec::new();
+    trie.retain_prefixes(|prefix| {
+        asked.push(prefix.to_vec());
+
+        if prefix.starts_with(b"aa") {
+            Retain::Drop
+        } else if prefix.starts_with(b"c") {
+            Retain::Keep
+        } else {
+            Retain::Descend
+        }
+    });
+
+    assert_eq!(trie.keys().collect::<Vec<_>>(), vec![
+        b"".to_vec(),
+        b"a".to_vec(),
+        b"ab".to_vec(),
+        b"b".to_vec(),
+        b"cca".to_vec(),
+        b"ccb".to_vec(),
+        b"ccc".to_vec(),
+        b"ccccdd".to_vec(),
+    ]);
+    assert_eq!(trie.len(), 8);
+    assert_eq!(format!("{:?}", trie), format!("{:?}", rebuild(&trie)));
+
+    // Whole subtrees are decided without visiting their keys, and no prefix is asked twice.
+    assert_eq!(asked, vec![
+        b"".to_vec(),
+        b"a".to_vec(),
+        b"aa".to_vec(),
+        b"ab".to_vec(),
+        b"b".to_vec(),
+        b"cc".to_vec(),
+    ]);
+
+    for seed in 0..10 {
+        let mut trie = random_map(seed, 200);
+        let expected = trie.iter()
+            .filter(|(key, _)| !key.starts_with(b"ab") && !key.starts_with(b"cca"))
+            .map(|(key, value)| (key, *value))
+            .collect::<Vec<_>>();
+
+        trie.retain_prefixes(|prefix| {
+            if prefix.starts_with(b"ab") || prefix.starts_with(b"cca") {
+                Retain::Drop
+            } else if b"ab".starts_with(prefix) || b"cca".starts_with(prefix) {
+                Retain::Descend
+            } else {
+                Retain::Keep
+            }
+        });
+
+        assert_eq!(trie.iter().map(|(key, value)| (key, *value)).collect::<Vec<_>>(), expected);
+        assert_eq!(trie.len(), expected.len());
+        assert_eq!(format!("{:?}", trie), format!("{:?}", rebuild(&trie)));
+    }
+}
+
+#[test]
+fn test_append() {
+    let mut trie = build_map();
+    let mut other = TrieMap::new();
+    other.insert(b"aa", 20);
+    other.insert(b"abc", 21);
+
+    trie.append(&mut other);
+
+    assert!(other.is_empty());
+    assert_eq!(trie.len(), 11);
+    assert_eq!(trie.get(b"aa"), Some(&20));
+    assert_eq!(trie.get(b"abc"), Some(&21));
+
+    let mut empty = TrieMap::new();
+    empty.append(&mut trie);
+    assert!(trie.is_empty());
+    assert_eq!(empty.len(), 11);
+}
+
+#[test]
+fn test_merge_with() {
+    use std::collections::BTreeMap;
+
+    let mut defaults = TrieMap::new();
+    defaults.insert(b"color", 1);
+    defaults.insert(b"color.fg", 2);
+    defaults.insert(b"font", 3);
+
+    let mut overrides = TrieMap::new();
+    overrides.insert(b"color.fg", 20);
+    overrides.insert(b"color.bg", 30);
+    overrides.insert(b"size", 40);
+
+    let mut conflicts = Vec::new();
+    defaults.merge_with(overrides, |key, default, value| {
+        conflicts.push(key.to_vec());
+        default + value
+    });
+
+    assert_eq!(conflicts, vec![b"color.fg".to_vec()]);
+    assert_eq!(defaults.iter().map(|(key, value)| (key, *value)).collect::<Vec<_>>(), vec![
+        (b"color".to_vec(), 1),
+        (b"color.bg".to_vec(), 30),
+        (b"color.fg".to_vec(), 22),
+        (b"font".to_vec(), 3),
+        (b"size".to_vec(), 40),
+    ]);
+    assert_eq!(defaults.len(), 5);
+
+    for seed in 0..20 {
+        let mut lhs = random_map(seed, 50);
+        let rhs = random_map(seed + 100, 50);
+
+        let mut expected: BTreeMap<Vec<u8>, usize> = lhs.iter()
+            .map(|(key, value)| (key, *value))
+            .collect();
+        for (key, value) in rhs.iter() {
+            let value = match expected.get(&key) {
+                Some(lhs) => lhs * 1000 + value,
+                None => *value,
+            };
+            expected.insert(key, value);
+        }
+
+        lhs.merge_with(rhs, |_, lhs, rhs| lhs * 1000 + rhs);
+
+        assert_eq!(lhs.iter().map(|(key, value)| (key, *value)).collect::<Vec<_>>(),
+                   expected.into_iter().collect::<Vec<_>>());
+        assert_eq!(format!("{:?}", lhs), format!("{:?}", rebuild(&lhs)));
+    }
+}
+
+#[test]
+fn test_cursor() {
+    let trie = build_map();
+    let mut cursor = trie.cursor();
+
+    assert_eq!(cursor.value(), Some(&0));
+    assert_eq!(cursor.next_bytes().collect::<Vec<_>>(), b"abc".to_vec());
+
+    // Step into the middle of the compressed "cc" edge.
+    assert!(cursor.step(b'c'));
+    assert_eq!(cursor.value(), None);
+    assert_eq!(cursor.next_bytes().collect::<Vec<_>>(), b"c".to_vec());
+    assert!(!cursor.step(b'a'));
+    assert_eq!(cursor.depth(), 1);
+
+    assert!(cursor.step(b'c'));
+    assert_eq!(cursor.next_bytes().collect::<Vec<_>>(), b"abc".to_vec());
+    assert!(cursor.step(b'b'));
+    assert_eq!(cursor.value(), Some(&8));
+    assert_eq!(cursor.depth(), 3);
+    assert_eq!(cursor.next_bytes().count(), 0);
+
+    cursor.reset();
+    assert_eq!(cursor.depth(), 0);
+    assert!(!cursor.step_many(b"aac"));
+    assert_eq!(cursor.depth(), 0);
+    assert!(cursor.step_many(b"aab"));
+    assert_eq!(cursor.value(), Some(&6));
+
+    let trie = random_map(13, 200);
+    let keys = trie.keys().collect::<Vec<_>>();
+
+    for len in 0..5 {
+        for probe in 0..3usize.pow(len) {
+            let probe: Vec<u8> = (0..len)
+                .map(|j| b"abc"[probe / 3usize.pow(j) % 3])
+                .collect();
+
+            let mut cursor = trie.cursor();
+            let found = cursor.step_many(&probe);
+            assert_eq!(found, keys.iter().any(|key| key.starts_with(&probe)));
+
+            if found {
+                assert_eq!(cursor.depth(), probe.len());
+                assert_eq!(cursor.value(), trie.iter().find(|(key, _)| *key == probe)
+                                               .map(|(_, value)| value));
+
+                let mut next = keys.iter()
+                    .filter(|key| key.len() > probe.len() && key.starts_with(&probe))
+                    .map(|key| key[probe.len()])
+                    .collect::<Vec<_>>();
+                next.dedup();
+                assert_eq!(cursor.next_bytes().collect::<Vec<_>>(), next);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_std_traits() {
+    use std::collections::BTreeMap;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    let mut trie: TrieMap<u32> = Default::default();
+    trie.extend(vec![(&b"b"[..], 2), (&b"a"[..], 1)]);
+    trie.extend(vec![(b"ab".to_vec(), 3)]);
+
+    assert_eq!(trie[&b"ab"[..]], 3);
+    assert_eq!(trie.len(), 3);
+
+    // Equality only depends on the entries, not the order they were inserted in.
+    let other: TrieMap<u32> = vec![(b"ab".to_vec(), 3), (b"b".to_vec(), 2), (b"a".to_vec(), 1)]
+        .into_iter()
+        .collect();
+    assert_eq!(trie, other);
+    assert_eq!(hash(&trie), hash(&other));
+
+    for (_, value) in &mut trie {
+        *value *= 10;
+    }
+    assert_eq!((&trie).into_iter().map(|(_, value)| *value).collect::<Vec<_>>(), vec![10, 30, 20]);
+    assert!(trie != other);
+
+    for seed in 0..10 {
+        let lhs = random_map(seed, 5);
+        let rhs = random_map(seed + 1, 5);
+
+        let lhs_map: BTreeMap<Vec<u8>, usize> =
+            lhs.iter().map(|(key, value)| (key, *value)).collect();
+        let rhs_map: BTreeMap<Vec<u8>, usize> =
+            rhs.iter().map(|(key, value)| (key, *value)).collect();
+
+        assert_eq!(lhs.cmp(&rhs), lhs_map.cmp(&rhs_map));
+        assert_eq!(rhs.partial_cmp(&lhs), rhs_map.partial_cmp(&lhs_map));
+        assert_eq!(lhs == rhs, lhs_map == rhs_map);
+    }
+}
+
+#[test]
+#[should_panic(expected = "no entry found for key")]
+fn test_index_missing() {
+    let trie = build_map();
+    let _ = trie[&b"abc"[..]];
+}
+
+#[test]
+fn test_tree() {
+    let mut trie = TrieMap::new();
+    trie.insert(b"ab", 1);
+    trie.insert(b"abc", 2);
+    trie.insert(b"b", 3);
+    trie.insert(b"b\n", 4);
+
+    assert_eq!(trie.tree().to_string(), "\
+trie
+|-- 'a' prefix \"b\" = 1
+|   `-- prefix \"c\" = 2
+`-- 'b' prefix \"\" = 3
+    `-- prefix \"\\n\" = 4
+");
+
+    assert_eq!(trie.to_dot(), "\
+digraph trie {
+    node0 [shape=circle, label=\"\"];
+    node1 [shape=box, peripheries=2, label=\"\\\"b\\\" = 1\"];
+    node2 [shape=box, peripheries=2, label=\"\\\"c\\\" = 2\"];
+    node1 -> node2 [label=\"b\"];
+    node0 -> node1 [label=\"a\"];
+    node3 [shape=box, peripheries=2, label=\"\\\"\\\" = 3\"];
+    node4 [shape=box, peripheries=2, label=\"\\\"\\\\n\\\" = 4\"];
+    node3 -> node4 [label=\"\"];
+    node0 -> node3 [label=\"b\"];
+}
+");
+
+    let empty: TrieMap<u32> = TrieMap::new();
+    assert_eq!(empty.tree().to_string(), "trie\n");
+    assert_eq!(empty.to_dot(), "digraph trie {\n    node0 [shape=circle, label=\"\"];\n}\n");
+}
+
+#[test]
+fn test_explain() {
+    fn step(kind: NodeKind, depth: usize, consumed: usize, key_len: usize) -> TraceStep {
+        TraceStep {
+            kind: kind,
+            depth: depth,
+            consumed: consumed,
+            key_len: key_len,
+        }
+    }
+
+    let mut trie = build_map();
+    trie.insert(b"dxyz", 10);
+
+    let trace = trie.explain(b"aab");
+    assert_eq!(trace.steps, vec![step(NodeKind::Trie, 0, 1, 0),
+                                 step(NodeKind::Trie, 1, 1, 0),
+                                 step(NodeKind::Trie, 2, 1, 0),
+                                 step(NodeKind::Prefix, 3, 0, 0)]);
+    assert_eq!(trace.matched, 3);
+    assert_eq!(trace.stop, StopReason::Found);
+
+    // The key ends partway through a compressed key.
+    let trace = trie.explain(b"dx");
+    assert_eq!(trace.steps, vec![step(NodeKind::Trie, 0, 1, 0), step(NodeKind::Prefix, 1, 1, 3)]);
+    assert_eq!(trace.stop, StopReason::NoValue);
+
+    let trace = trie.explain(b"dxq");
+    assert_eq!(trace.steps, vec![step(NodeKind::Trie, 0, 1, 0), step(NodeKind::Prefix, 1, 1, 3)]);
+    assert_eq!(trace.matched, 2);
+    assert_eq!(trace.stop, StopReason::Diverged(1));
+
+    let trace = trie.explain(b"dxyzw");
+    assert_eq!(trace.matched, 4);
+    assert_eq!(trace.stop, StopReason::MissingChild(b'w'));
+
+    let trace = trie.explain(b"ccd");
+    assert_eq!(trace.steps, vec![step(NodeKind::Trie, 0, 1, 0),
+                                 step(NodeKind::Prefix, 1, 1, 1),
+                                 step(NodeKind::Trie, 2, 0, 0)]);
+    assert_eq!(trace.stop, StopReason::MissingChild(b'd'));
+
+    assert_eq!(trie.explain(b"c").stop, StopReason::NoValue);
+    assert_eq!(trie.explain(b"e").stop, StopReason::MissingChild(b'e'));
+    assert_eq!(trie.explain(b"").stop, StopReason::Found);
+
+    for seed in 0..10 {
+        let trie = random_map(seed, 20);
+        let other = random_map(seed + 100, 20);
+
+        for (key, _) in other.iter() {
+            let trace = trie.explain(&key);
+            assert_eq!(trace.stop == StopReason::Found, trie.contains_key(&key));
+            assert!(trace.matched <= key.len());
+        }
+    }
+}
+
+#[test]
+fn test_get_or_mismatch() {
+    let trie: TrieMap<u32> = vec![(&b"co"[..], 1), (b"color", 2), (b"column", 3)]
+        .into_iter()
+        .collect();
+
+    assert_eq!(trie.get_or_mismatch(b"color"), Ok(&2));
+    assert_eq!(trie.get_or_mismatch(b"colr"),
+               Err(Mismatch { matched: 3, kind: MismatchKind::Extends(2) }));
+    assert_eq!(trie.get_or_mismatch(b"colo"),
+               Err(Mismatch { matched: 4, kind: MismatchKind::Prefix }));
+    assert_eq!(trie.get_or_mismatch(b"c"),
+               Err(Mismatch { matched: 1, kind: MismatchKind::Prefix }));
+    assert_eq!(trie.get_or_mismatch(b"colors"),
+               Err(Mismatch { matched: 5, kind: MismatchKind::Extends(5) }));
+    assert_eq!(trie.get_or_mismatch(b"size"),
+               Err(Mismatch { matched: 0, kind: MismatchKind::Diverged }));
+
+    let empty: TrieMap<u32> = TrieMap::new();
+    assert_eq!(empty.get_or_mismatch(b""),
+               Err(Mismatch { matched: 0, kind: MismatchKind::Diverged }));
+
+    for seed in 0..10 {
+        let trie = random_map(seed, 20);
+        let keys: Vec<Vec<u8>> = trie.keys().collect();
+
+        for (key, _) in random_map(seed + 100, 20).iter() {
+            let expected = match trie.get(&key) {
+                Some(value) => Ok(value),
+                None => {
+                    let matched = keys.iter()
+                        .map(|stored| {
+                            stored.iter()
+                                .zip(key.iter())
+                                .take_while(|&(lhs, rhs)| lhs == rhs)
+                                .count()
+                        })
+                        .max()
+                        .unwrap_or(0);
+
+                    let kind = if keys.iter().any(|stored| stored.starts_with(&key)) {
+                        MismatchKind::Prefix
+                    } else {
+                        keys.iter()
+                            .filter(|stored| key.starts_with(stored))
+                            .map(|stored| stored.len())
+                            .max()
+                            .map_or(MismatchKind::Diverged, MismatchKind::Extends)
+                    };
+
+                    Err(Mismatch { matched: matched, kind: kind })
+                }
+            };
+
+            assert_eq!(trie.get_or_mismatch(&key), expected);
+        }
+    }
+}
+
+#[test]
+fn test_classify() {
+    let trie: TrieMap<u32> = vec![(&b"net"[..], 1), (b"net.http", 2), (b"net.ipv4", 3)]
+        .into_iter()
+        .collect();
+
+    assert_eq!(trie.classify(b"net"), KeyRelation::Stored);
+    assert_eq!(trie.classify(b"net.ipv4"), KeyRelation::Stored);
+    assert_eq!(trie.classify(b"net."), KeyRelation::Prefix);
+    assert_eq!(trie.classify(b"n"), KeyRelation::Prefix);
+    assert_eq!(trie.classify(b""), KeyRelation::Prefix);
+    assert_eq!(trie.classify(b"net.ipv6"), KeyRelation::Extends);
+    assert_eq!(trie.classify(b"net.http.proxy"), KeyRelation::Extends);
+    assert_eq!(trie.classify(b"fs"), KeyRelation::Unrelated);
+    assert_eq!(trie.classify(b"nfs"), KeyRelation::Unrelated);
+
+    for seed in 0..10 {
+        let trie = random_map(seed, 20);
+        let keys: Vec<Vec<u8>> = trie.keys().collect();
+
+        for (key, _) in random_map(seed + 100, 20).iter() {
+            let expected = if trie.contains_key(&key) {
+                KeyRelation::Stored
+            } else if keys.iter().any(|stored| stored.starts_with(&key)) {
+                KeyRelation::Prefix
+            } else if keys.iter().any(|stored| key.starts_with(stored)) {
+                KeyRelation::Extends
+            } else {
+                KeyRelation::Unrelated
+            };
+
+            assert_eq!(trie.classify(&key), expected);
+        }
+    }
+}
+
+#[test]
+fn test_validate() {
+    assert_eq!(TrieMap::<u32>::new().validate(), Ok(()));
+    assert_eq!(build_map().validate(), Ok(()));
+
+    for seed in 0..10 {
+        let mut trie = random_map(seed, 100);
+        assert_eq!(trie.validate(), Ok(()));
+
+        for (key, _) in random_map(seed + 100, 20).iter() {
+            *trie.entry(&key).or_insert(0) += 1;
+            assert_eq!(trie.validate(), Ok(()));
+        }
+
+        let keys: Vec<Vec<u8>> = trie.keys().collect();
+        for key in keys.iter().step_by(3) {
+            trie.remove(key);
+            assert_eq!(trie.validate(), Ok(()));
+        }
+
+        let mut right = trie.split_off(b"b");
+        assert_eq!(trie.validate(), Ok(()));
+        assert_eq!(right.validate(), Ok(()));
+
+        let prefixed = right.split_off_prefix(b"ca");
+        assert_eq!(right.validate(), Ok(()));
+        assert_eq!(prefixed.validate(), Ok(()));
+
+        right.retain(|key, _| key.len() % 2 == 0);
+        assert_eq!(right.validate(), Ok(()));
+
+        trie.merge_with(right, |_, lhs, rhs| lhs + rhs);
+        assert_eq!(trie.validate(), Ok(()));
+    }
+
+    assert_eq!(InvariantError::SparseTrie(b"ab".to_vec()).to_string(),
+               "the trie node at \"ab\" has fewer than two children");
+}
+
 /*
 #[test]
 fn quickcheck_insert() {
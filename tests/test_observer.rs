@@ -0,0 +1,151 @@
+extern crate prefix_trie;
+
+use prefix_trie::TrieMap;
+use prefix_trie::map::Entry;
+use prefix_trie::observer::{NodeKind, TrieObserver};
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Created(NodeKind),
+    Split(usize, usize),
+    Burst,
+    SplitFront(usize),
+    Replaced(Vec<u8>),
+}
+
+#[derive(Default)]
+struct Recorder {
+    events: Vec<Event>,
+}
+
+impl TrieObserver for Recorder {
+    fn node_created(&mut self, kind: NodeKind) {
+        self.events.push(Event::Created(kind));
+    }
+
+    fn prefix_split(&mut self, len: usize, pos: usize) {
+        self.events.push(Event::Split(len, pos));
+    }
+
+    fn burst_into_trie(&mut self) {
+        self.events.push(Event::Burst);
+    }
+
+    fn split_front(&mut self, len: usize) {
+        self.events.push(Event::SplitFront(len));
+    }
+
+    fn value_replaced(&mut self, key: &[u8]) {
+        self.events.push(Event::Replaced(key.to_owned()));
+    }
+}
+
+fn insert(trie: &mut TrieMap<u32, Recorder>, key: &[u8], value: u32) -> Vec<Event> {
+    trie.insert(key, value);
+    trie.observer_mut().events.drain(..).collect()
+}
+
+#[test]
+fn test_insert_events() {
+    let mut trie = TrieMap::with_observer(Recorder::default());
+
+    assert_eq!(insert(&mut trie, b"abc", 1), vec![Event::Created(NodeKind::Prefix)]);
+    assert_eq!(insert(&mut trie, b"abd", 2),
+               vec![Event::Split(2, 1),
+                    Event::Created(NodeKind::Trie),
+                    Event::Created(NodeKind::Prefix),
+                    Event::Created(NodeKind::Prefix)]);
+    assert_eq!(insert(&mut trie, b"abd", 3), vec![Event::Replaced(b"abd".to_vec())]);
+    assert_eq!(insert(&mut trie, b"a", 4),
+               vec![Event::Split(1, 0), Event::Created(NodeKind::Prefix)]);
+
+    assert_eq!(insert(&mut trie, b"xyz", 5), vec![Event::Created(NodeKind::Prefix)]);
+    assert_eq!(insert(&mut trie, b"xab", 6),
+               vec![Event::Burst,
+                    Event::Created(NodeKind::Trie),
+                    Event::Created(NodeKind::Prefix),
+                    Event::Created(NodeKind::Prefix)]);
+
+    let plain: TrieMap<u32> = trie.iter().map(|(key, value)| (key, *value)).collect();
+    assert_eq!(format!("{}", trie.tree()), format!("{}", plain.tree()));
+    assert_eq!(trie.len(), 5);
+}
+
+#[test]
+fn test_entry_events() {
+    let mut recorder = Recorder::default();
+
+    {
+        let mut trie = TrieMap::with_observer(&mut recorder);
+        trie.insert(b"ab", 1);
+
+        *trie.entry(b"ab").or_insert(0) += 1;
+        trie.entry(b"abc").or_insert(3);
+
+        match trie.entry(b"ab") {
+            Entry::Occupied(mut entry) => { entry.insert(5); }
+            Entry::Vacant(_) => panic!(),
+        }
+
+        assert_eq!(trie.get(b"ab"), Some(&5));
+    }
+
+    assert_eq!(recorder.events,
+               vec![Event::Created(NodeKind::Prefix),
+                    Event::Created(NodeKind::Prefix),
+                    Event::Replaced(b"ab".to_vec())]);
+}
+
+#[test]
+fn test_merge_events() {
+    let mut trie = TrieMap::with_observer(Recorder::default());
+    trie.insert(b"ab", 1);
+    trie.observer_mut().events.clear();
+
+    let other: TrieMap<u32> = vec![(&b"ab"[..], 2), (b"ac", 3)].into_iter().collect();
+    trie.merge_with(other, |_, lhs, rhs| lhs + rhs);
+
+    assert_eq!(trie.observer().events,
+               vec![Event::Burst,
+                    Event::SplitFront(1),
+                    Event::Created(NodeKind::Prefix),
+                    Event::Created(NodeKind::Trie),
+                    Event::Burst,
+                    Event::Created(NodeKind::Trie),
+                    Event::Burst,
+                    Event::Created(NodeKind::Trie),
+                    Event::Replaced(b"ab".to_vec()),
+                    Event::Created(NodeKind::Prefix)]);
+    assert_eq!(trie.get(b"ab"), Some(&3));
+}
+
+#[test]
+fn test_compact_events() {
+    fn build() -> TrieMap<u32, Recorder> {
+        let mut trie = TrieMap::with_observer(Recorder::default());
+        trie.insert(b"a", 1);
+        trie.insert(b"ab", 2);
+        trie.insert(b"ac", 3);
+        trie.observer_mut().events.clear();
+        trie
+    }
+
+    // Dropping a key below the trie node at "a" leaves it with one child, so it is compacted into
+    // a new prefix node that holds its value.
+    let mut trie = build();
+    trie.remove(b"ab");
+    assert_eq!(trie.observer().events, vec![Event::Created(NodeKind::Prefix)]);
+
+    let mut trie = build();
+    trie.retain(|key, _| key != b"ab");
+    assert_eq!(trie.observer().events, vec![Event::Created(NodeKind::Prefix)]);
+
+    let mut trie = build();
+    trie.split_off(b"ac");
+    assert_eq!(trie.observer().events, vec![Event::Created(NodeKind::Prefix)]);
+
+    // The trie node still has two children after this, so nothing is rebuilt.
+    let mut trie = build();
+    trie.remove(b"a");
+    assert_eq!(trie.observer().events, vec![]);
+}
@@ -0,0 +1,126 @@
+extern crate prefix_trie;
+
+mod common;
+
+use std::collections::BTreeSet;
+
+use prefix_trie::TrieSet;
+
+use common::Rng;
+
+fn random_keys(seed: u32, n: usize) -> Vec<Vec<u8>> {
+    let mut rng = Rng::new(seed);
+    (0..n).map(|_| rng.key(b"abc", 0..6)).collect()
+}
+
+#[test]
+fn test_insert_remove() {
+    assert!(TrieSet::default().is_empty());
+
+    let mut set = TrieSet::new();
+    assert!(set.is_empty());
+
+    assert!(set.insert(b"abc"));
+    assert!(set.insert(b"ab"));
+    assert!(!set.insert(b"abc"));
+    assert_eq!(set.len(), 2);
+
+    assert!(set.contains(b"ab"));
+    assert!(!set.contains(b"a"));
+
+    assert!(set.remove(b"ab"));
+    assert!(!set.remove(b"ab"));
+    assert_eq!(set.iter().collect::<Vec<_>>(), vec![b"abc".to_vec()]);
+}
+
+#[test]
+fn test_set_algebra() {
+    let lhs: TrieSet = [&b"allow"[..], b"allowed", b"deny", b"ignore"].iter().cloned().collect();
+    let rhs: TrieSet = [&b"allowed"[..], b"deny", b"denied"].iter().cloned().collect();
+
+    assert_eq!(lhs.intersection(&rhs).collect::<Vec<_>>(),
+               vec![b"allowed".to_vec(), b"deny".to_vec()]);
+    assert_eq!(lhs.difference(&rhs).collect::<Vec<_>>(),
+               vec![b"allow".to_vec(), b"ignore".to_vec()]);
+    assert!(!lhs.is_disjoint(&rhs));
+    assert!(!rhs.is_subset(&lhs));
+
+    for seed in 0..20 {
+        let lhs_keys = random_keys(seed, 30);
+        let rhs_keys = random_keys(seed + 100, 30);
+
+        let lhs: TrieSet = lhs_keys.iter().map(|key| &key[..]).collect();
+        let rhs: TrieSet = rhs_keys.iter().map(|key| &key[..]).collect();
+        let lhs_set: BTreeSet<Vec<u8>> = lhs_keys.iter().cloned().collect();
+        let rhs_set: BTreeSet<Vec<u8>> = rhs_keys.iter().cloned().collect();
+
+        assert_eq!(lhs.iter().collect::<Vec<_>>(),
+                   lhs_set.iter().cloned().collect::<Vec<_>>());
+        assert_eq!(lhs.union(&rhs).collect::<Vec<_>>(),
+                   lhs_set.union(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(lhs.intersection(&rhs).collect::<Vec<_>>(),
+                   lhs_set.intersection(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(lhs.difference(&rhs).collect::<Vec<_>>(),
+                   lhs_set.difference(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(lhs.symmetric_difference(&rhs).collect::<Vec<_>>(),
+                   lhs_set.symmetric_difference(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(lhs.is_disjoint(&rhs), lhs_set.is_disjoint(&rhs_set));
+        assert_eq!(lhs.is_subset(&rhs), lhs_set.is_subset(&rhs_set));
+
+        let inner: TrieSet = lhs.intersection(&rhs).collect::<Vec<_>>()
+            .iter()
+            .map(|key| &key[..])
+            .collect();
+        assert!(inner.is_subset(&lhs));
+        assert!(lhs.is_superset(&inner));
+        assert!(inner.is_disjoint(&lhs.symmetric_difference(&rhs)
+                                      .collect::<Vec<_>>()
+                                      .iter()
+                                      .map(|key| &key[..])
+                                      .collect()));
+    }
+}
+
+#[test]
+fn test_set_algebra_compressed() {
+    fn collect<I: Iterator<Item=Vec<u8>>>(iter: I) -> Vec<Vec<u8>> {
+        let (_, upper) = iter.size_hint();
+        let keys: Vec<Vec<u8>> = iter.collect();
+        assert!(keys.len() <= upper.unwrap());
+        keys
+    }
+
+    // Long keys over a small alphabet leave the sets with compressed nodes that the other set
+    // branches in the middle of.
+    for seed in 0..20 {
+        let mut rng = Rng::new(seed);
+        let lhs_keys: Vec<Vec<u8>> = (0..40).map(|_| rng.key(b"ab", 0..12)).collect();
+        let rhs_len = seed as usize * 3;
+        let rhs_keys: Vec<Vec<u8>> = (0..rhs_len).map(|_| rng.key(b"ab", 0..12)).collect();
+
+        let lhs: TrieSet = lhs_keys.iter().map(|key| &key[..]).collect();
+        let rhs: TrieSet = rhs_keys.iter().map(|key| &key[..]).collect();
+        let lhs_set: BTreeSet<Vec<u8>> = lhs_keys.iter().cloned().collect();
+        let rhs_set: BTreeSet<Vec<u8>> = rhs_keys.iter().cloned().collect();
+
+        assert_eq!(collect(lhs.union(&rhs)),
+                   lhs_set.union(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(collect(lhs.intersection(&rhs)),
+                   lhs_set.intersection(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(collect(lhs.difference(&rhs)),
+                   lhs_set.difference(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(collect(rhs.difference(&lhs)),
+                   rhs_set.difference(&lhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(collect(lhs.symmetric_difference(&rhs)),
+                   lhs_set.symmetric_difference(&rhs_set).cloned().collect::<Vec<_>>());
+        assert_eq!(lhs.is_subset(&rhs), lhs_set.is_subset(&rhs_set));
+        assert_eq!(rhs.is_subset(&lhs), rhs_set.is_subset(&lhs_set));
+        assert_eq!(lhs.is_disjoint(&rhs), lhs_set.is_disjoint(&rhs_set));
+
+        let subset: TrieSet = lhs_keys.iter().step_by(3).map(|key| &key[..]).collect();
+        assert!(subset.is_subset(&lhs));
+        assert!(TrieSet::new().is_subset(&subset));
+        assert_eq!(collect(subset.union(&TrieSet::new())), collect(subset.iter()));
+        assert_eq!(collect(TrieSet::new().difference(&subset)), Vec::<Vec<u8>>::new());
+    }
+}
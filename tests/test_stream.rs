@@ -0,0 +1,133 @@
+extern crate prefix_trie;
+
+mod common;
+
+use prefix_trie::TrieMap;
+use prefix_trie::stream::{Match, MatchKind, StreamMatcher};
+
+use common::Rng;
+
+fn keywords() -> TrieMap<u32> {
+    let mut map = TrieMap::new();
+    map.insert(b"GET", 1);
+    map.insert(b"GETS", 2);
+    map.insert(b"ET", 3);
+    map.insert(b"HEAD", 4);
+    map.insert(b"HEADER", 5);
+    map
+}
+
+/// Feeds `input` to a matcher in chunks of `size` bytes, and collects every match as
+/// `(start, end, value)`.
+fn run(map: &TrieMap<u32>,
+       kind: MatchKind,
+       input: &[u8],
+       size: usize) -> Vec<(usize, usize, u32)> {
+    let mut matcher = StreamMatcher::new(map, kind);
+    let mut matches = Vec::new();
+
+    for chunk in input.chunks(size) {
+        matches.extend(matcher.feed(chunk));
+    }
+
+    matches.extend(matcher.finish());
+    matches.into_iter().map(|m| (m.start, m.end, *m.value)).collect()
+}
+
+#[test]
+fn test_all() {
+    let map = keywords();
+
+    let mut matcher = StreamMatcher::new(&map, MatchKind::All);
+    assert_eq!(matcher.feed(b"xxGE"), vec![]);
+    assert_eq!(matcher.feed(b"TS"), vec![
+        Match { start: 2, end: 5, value: &1 },
+        Match { start: 3, end: 5, value: &3 },
+        Match { start: 2, end: 6, value: &2 },
+    ]);
+    assert_eq!(matcher.offset(), 6);
+    assert_eq!(matcher.finish(), vec![]);
+
+    for size in 1..8 {
+        assert_eq!(run(&map, MatchKind::All, b"GETSHEADERGET", size), vec![
+            (0, 3, 1),
+            (1, 3, 3),
+            (0, 4, 2),
+            (4, 8, 4),
+            (4, 10, 5),
+            (10, 13, 1),
+            (11, 13, 3),
+        ]);
+    }
+}
+
+#[test]
+fn test_longest() {
+    let map = keywords();
+
+    let mut matcher = StreamMatcher::new(&map, MatchKind::Longest);
+    assert_eq!(matcher.feed(b"HEA"), vec![]);
+    assert_eq!(matcher.feed(b"Dx"), vec![Match { start: 0, end: 4, value: &4 }]);
+    assert_eq!(matcher.feed(b"GET"), vec![]);
+    assert_eq!(matcher.finish(), vec![Match { start: 5, end: 8, value: &1 }]);
+
+    for size in 1..8 {
+        assert_eq!(run(&map, MatchKind::Longest, b"GETSHEADERGETHEADE", size), vec![
+            (0, 4, 2),
+            (4, 10, 5),
+            (10, 13, 1),
+            (13, 17, 4),
+        ]);
+    }
+}
+
+#[test]
+fn test_matches_brute_force() {
+    let mut map = TrieMap::new();
+    let mut keys = Vec::new();
+
+    let mut rng = Rng::new(17);
+    for i in 0..30 {
+        let key = rng.key(b"ab", 1..5);
+
+        map.insert(&key, i);
+        keys.push(key);
+    }
+
+    let input: Vec<u8> = (0..200)
+        .map(|_| b"ab"[(rng.next_u32() >> 16) as usize % 2])
+        .collect();
+
+    let mut all = Vec::new();
+    for end in 1..input.len() + 1 {
+        for start in 0..end {
+            if keys.contains(&input[start..end].to_vec()) {
+                all.push((start, end));
+            }
+        }
+    }
+
+    let mut longest = Vec::new();
+    let mut start = 0;
+    while start < input.len() {
+        let end = (start + 1..input.len() + 1)
+            .rev()
+            .find(|&end| keys.contains(&input[start..end].to_vec()));
+
+        match end {
+            Some(end) => {
+                longest.push((start, end));
+                start = end;
+            }
+            None => { start += 1; }
+        }
+    }
+
+    for &size in &[1, 2, 3, 7, 200] {
+        let found = run(&map, MatchKind::All, &input, size);
+        assert_eq!(found.iter().map(|&(start, end, _)| (start, end)).collect::<Vec<_>>(), all);
+
+        let found = run(&map, MatchKind::Longest, &input, size);
+        assert_eq!(found.iter().map(|&(start, end, _)| (start, end)).collect::<Vec<_>>(), longest);
+    }
+}